@@ -0,0 +1,563 @@
+extern crate reqwest;
+extern crate serde_json;
+extern crate url;
+
+extern crate futures;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, Future};
+use serde_json::json;
+use url::percent_encoding::percent_decode;
+
+use matrix_async_client::AsyncMatrixClient;
+use matrix_client::*;
+use matrix_core::*;
+use matrix_transport::*;
+
+// An in-memory homeserver for tests. It is plugged into a MatrixClient as its HttpTransport, so
+// nothing ever touches the network. The endpoints jacobian uses have simple built-in behaviour
+// (one account, rooms which accept any event, a queue of sync responses) and any request can be
+// answered with a scripted response instead, to exercise the client's error paths.
+
+pub const FAKE_HOMESERVER_URL: &'static str = "https://fake.example.org";
+pub const FAKE_SERVER_NAME: &'static str = "fake.example.org";
+
+const FAKE_ACCESS_TOKEN: &'static str = "fake_access_token";
+const FAKE_DEVICE_ID: &'static str = "FAKEDEVICE";
+
+// The default retry policy, but without the waiting, so tests of retries run quickly
+pub fn quick_retries() -> RetryPolicy {
+    RetryPolicy {
+        initial_backoff: Duration::from_millis(1),
+        .. RetryPolicy::default()
+    }
+}
+
+// What the fake homeserver does with a request, instead of what it would normally do
+#[derive(Debug, Clone)]
+pub enum FakeResponse {
+    Json(u16, serde_json::Value),
+
+    // A successful response which isn't JSON, e.g. a download
+    Bytes(String, Vec<u8>),
+
+    // The request never gets an answer
+    Timeout,
+
+    // The answer takes this long to arrive, so the attempt times out first if its timeouts are
+    // any shorter
+    Slow(Duration, Box<FakeResponse>)
+}
+
+impl FakeResponse {
+    pub fn error(status: u16, errcode: &str, error: &str) -> FakeResponse {
+        FakeResponse::Json(status, json!({ "errcode": errcode, "error": error }))
+    }
+
+    pub fn rate_limited(retry_after_ms: u64) -> FakeResponse {
+        FakeResponse::Json(429, json!({
+            "errcode": "M_LIMIT_EXCEEDED",
+            "error": "Too many requests",
+            "retry_after_ms": retry_after_ms
+        }))
+    }
+
+    pub fn slow(delay: Duration, response: FakeResponse) -> FakeResponse {
+        FakeResponse::Slow(delay, Box::new(response))
+    }
+}
+
+// A request as the fake homeserver saw it, with the client API prefix stripped off the path
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: reqwest::Method,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub access_token: Option<String>,
+    pub content_type: Option<String>,
+
+    // The body exactly as it was sent, for requests which aren't JSON
+    pub raw_body: Option<Vec<u8>>,
+
+    pub body: Option<serde_json::Value>,
+
+    // The timeouts the client gave this attempt
+    pub timeouts: Timeouts
+}
+
+// An event a client sent into a room
+#[derive(Debug, Clone)]
+pub struct SentEvent {
+    pub room_id: String,
+    pub event_type: String,
+    pub txn_id: String,
+    pub event_id: String,
+    pub content: serde_json::Value,
+
+    // For redactions, the event being redacted
+    pub redacts: Option<String>
+}
+
+#[derive(Default)]
+struct FakeState {
+    password: String,
+    user_id: String,
+    logged_in: bool,
+    scripted: Vec<(reqwest::Method, String, FakeResponse)>,
+    syncs: VecDeque<serde_json::Value>,
+    message_pages: HashMap<String, VecDeque<(Vec<serde_json::Value>, String)>>,
+    sync_count: usize,
+    requests: Vec<RecordedRequest>,
+    sent_events: Vec<SentEvent>,
+    filters: Vec<serde_json::Value>,
+    room_state: HashMap<String, Vec<serde_json::Value>>,
+    created_rooms: usize,
+    media: Vec<(String, Vec<u8>)>,
+    joined_rooms: Vec<String>
+}
+
+pub struct FakeHomeserver {
+    state: Mutex<FakeState>
+}
+
+impl FakeHomeserver {
+    // A homeserver with a single account which accepts `password`
+    pub fn new(localpart: &str, password: &str) -> Arc<FakeHomeserver> {
+        Arc::new(FakeHomeserver {
+            state: Mutex::new(FakeState {
+                password: String::from(password),
+                user_id: format!("@{}:{}", localpart, FAKE_SERVER_NAME),
+                .. FakeState::default()
+            })
+        })
+    }
+
+    // A client which talks to this homeserver and nothing else
+    pub fn client(self: &Arc<Self>) -> MatrixClient {
+        MatrixClient::with_transport(FAKE_HOMESERVER_URL, None, Box::new(self.clone()))
+    }
+
+    // A client which is already logged in to the fake account
+    pub fn logged_in_client(self: &Arc<Self>) -> MatrixClient {
+        let mut client = self.client();
+        let password = self.state().password.clone();
+        let localpart = self.state().user_id.clone();
+        client.login(localpart.as_str(), password.as_str()).expect("login to the fake homeserver failed");
+        client
+    }
+
+    pub fn async_client(self: &Arc<Self>) -> AsyncMatrixClient {
+        AsyncMatrixClient::with_transport(FAKE_HOMESERVER_URL, None, Box::new(self.clone()))
+    }
+
+    // The fake answers straight away, so logging in doesn't need a runtime
+    pub fn logged_in_async_client(self: &Arc<Self>) -> AsyncMatrixClient {
+        let client = self.async_client();
+        let password = self.state().password.clone();
+        let localpart = self.state().user_id.clone();
+        client.login(localpart.as_str(), password.as_str()).wait().expect("login to the fake homeserver failed");
+        client
+    }
+
+    fn state(&self) -> MutexGuard<FakeState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn user_id(&self) -> UserId {
+        UserId::parse(self.state().user_id.as_str()).unwrap()
+    }
+
+    // The next request to `method` whose path (without the /_matrix/client/r0/ prefix) starts
+    // with `path` gets `response`. Scripted responses are used up in the order they were added.
+    pub fn respond_with(&self, method: reqwest::Method, path: &str, response: FakeResponse) -> () {
+        self.state().scripted.push((method, String::from(path), response));
+    }
+
+    // Queues up a raw sync response. A next_batch is filled in if it doesn't have one. Once the
+    // queue runs dry syncs come back without a next_batch, which stops the bot.
+    pub fn push_sync(&self, mut sync: serde_json::Value) -> () {
+        let mut state = self.state();
+        state.sync_count += 1;
+
+        if sync.get("next_batch").is_none() {
+            sync["next_batch"] = json!(format!("s{}", state.sync_count));
+        }
+
+        state.syncs.push_back(sync);
+    }
+
+    pub fn push_empty_sync(&self) -> () {
+        self.push_sync(json!({
+            "rooms": { "join": {}, "invite": {}, "leave": {} },
+            "presence": { "events": [] }
+        }));
+    }
+
+    // A text message event as it would appear in a sync or a page of room history
+    pub fn text_message(event_id: &str, sender: &str, body: &str) -> serde_json::Value {
+        json!({
+            "type": ROOM_MESSAGE_EVENT_TYPE,
+            "event_id": event_id,
+            "sender": sender,
+            "origin_server_ts": 1550000000000i64,
+            "content": { "msgtype": "m.text", "body": body }
+        })
+    }
+
+    // Queues up a sync in which each (sender, body) pair arrives as a text message in `room_id`
+    pub fn push_messages(&self, room_id: &str, messages: &[(&str, &str)]) -> () {
+        let events: Vec<serde_json::Value> = messages.iter().enumerate().map(|(i, &(sender, body))| {
+            let event_id = format!("$incoming{}:{}", i, FAKE_SERVER_NAME);
+            FakeHomeserver::text_message(event_id.as_str(), sender, body)
+        }).collect();
+
+        self.push_events(room_id, events);
+    }
+
+    // Queues up a sync in which the given events arrive in the timeline of `room_id`
+    pub fn push_events(&self, room_id: &str, events: Vec<serde_json::Value>) -> () {
+        self.push_timeline(room_id, events, false, "p0");
+    }
+
+    // Queues up a sync whose timeline for `room_id` is limited, i.e. the server left out the
+    // events before these ones. They can be fetched from /messages starting at `prev_batch`.
+    pub fn push_limited_events(&self, room_id: &str, prev_batch: &str, events: Vec<serde_json::Value>) -> () {
+        self.push_timeline(room_id, events, true, prev_batch);
+    }
+
+    fn push_timeline(&self, room_id: &str, events: Vec<serde_json::Value>, limited: bool, prev_batch: &str) -> () {
+        let mut join = serde_json::Map::new();
+        join.insert(String::from(room_id), json!({
+            "timeline": { "limited": limited, "prev_batch": prev_batch, "events": events }
+        }));
+
+        self.push_sync(json!({
+            "rooms": { "join": join, "invite": {}, "leave": {} },
+            "presence": { "events": [] }
+        }));
+    }
+
+    // Queues up a page of history for /messages in `room_id` to return, in the order it should
+    // be returned (newest first when paging backwards), with `end` as the token for the next
+    // page. Once a room's pages run out, /messages comes back empty.
+    pub fn push_messages_page(&self, room_id: &str, events: Vec<serde_json::Value>, end: &str) -> () {
+        self.state().message_pages.entry(String::from(room_id)).or_default().push_back((events, String::from(end)));
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state().requests.clone()
+    }
+
+    // Every request made to an endpoint, e.g. requests_to("sync")
+    pub fn requests_to(&self, path: &str) -> Vec<RecordedRequest> {
+        self.requests().into_iter().filter(|r| r.path.starts_with(path)).collect()
+    }
+
+    pub fn sent_events(&self) -> Vec<SentEvent> {
+        self.state().sent_events.clone()
+    }
+
+    pub fn joined_rooms(&self) -> Vec<String> {
+        self.state().joined_rooms.clone()
+    }
+
+    pub fn is_logged_in(&self) -> bool {
+        self.state().logged_in
+    }
+
+    fn record(request: &MatrixRequest, timeouts: &Timeouts) -> RecordedRequest {
+        let segments: Vec<String> = request.url.path_segments()
+                                          .map(|segments| segments.map(|s| percent_decode(s.as_bytes()).decode_utf8_lossy().into_owned()).collect())
+                                          .unwrap_or_else(Vec::new);
+
+        // Strip /_matrix/client/r0 (or /_matrix/client for the unversioned endpoints)
+        let skip = if segments.len() > 2 && segments[2] == "r0" { 3 } else { 2 };
+        let path = segments.iter().skip(skip).cloned().collect::<Vec<String>>().join("/");
+
+        RecordedRequest {
+            method: request.method.clone(),
+            path: path,
+            query: request.url.query_pairs().into_owned().collect(),
+            access_token: request.access_token.clone(),
+            content_type: request.content_type.clone(),
+            raw_body: request.body.clone(),
+            body: request.body.as_ref().and_then(|body| serde_json::from_slice(body).ok()),
+            timeouts: *timeouts
+        }
+    }
+
+    fn take_scripted(state: &mut FakeState, request: &RecordedRequest) -> Option<FakeResponse> {
+        let position = state.scripted.iter().position(|&(ref method, ref path, _)| {
+            *method == request.method && request.path.starts_with(path.as_str())
+        });
+
+        position.map(|i| state.scripted.remove(i).2)
+    }
+
+    fn handle(state: &mut FakeState, request: &RecordedRequest) -> FakeResponse {
+        let segments: Vec<&str> = request.path.split('/').collect();
+
+        match (&request.method, segments.as_slice()) {
+            (&reqwest::Method::GET, &["versions"]) => {
+                return FakeResponse::Json(200, json!({ "versions": ["r0.5.0"] }));
+            },
+            (&reqwest::Method::POST, &["login"]) => {
+                return FakeHomeserver::login(state, request);
+            },
+            _ => ()
+        };
+
+        match request.access_token {
+            Some(ref access_token) if state.logged_in && access_token == FAKE_ACCESS_TOKEN => (),
+            Some(_) => { return FakeResponse::error(401, "M_UNKNOWN_TOKEN", "Unrecognised access token"); },
+            None => { return FakeResponse::error(401, "M_MISSING_TOKEN", "Missing access token"); }
+        };
+
+        match (&request.method, segments.as_slice()) {
+            (&reqwest::Method::POST, &["logout"]) | (&reqwest::Method::POST, &["logout", "all"]) => {
+                state.logged_in = false;
+                FakeResponse::Json(200, json!({}))
+            },
+            (&reqwest::Method::GET, &["sync"]) => {
+                match state.syncs.pop_front() {
+                    Some(sync) => FakeResponse::Json(200, sync),
+                    None => FakeResponse::Json(200, json!({
+                        "rooms": { "join": {}, "invite": {}, "leave": {} },
+                        "presence": { "events": [] }
+                    }))
+                }
+            },
+            (&reqwest::Method::POST, &["user", user_id, "filter"]) => {
+                if user_id != state.user_id {
+                    return FakeResponse::error(403, "M_FORBIDDEN", "Cannot create filters for other users");
+                }
+                state.filters.push(request.body.clone().unwrap_or(json!({})));
+                FakeResponse::Json(200, json!({ "filter_id": format!("{}", state.filters.len() - 1) }))
+            },
+            (&reqwest::Method::GET, &["user", _, "filter", filter_id]) => {
+                match filter_id.parse::<usize>().ok().and_then(|i| state.filters.get(i)) {
+                    Some(filter) => FakeResponse::Json(200, filter.clone()),
+                    None => FakeResponse::error(404, "M_NOT_FOUND", "No such filter")
+                }
+            },
+            // The creator is joined to the new room straight away
+            (&reqwest::Method::POST, &["createRoom"]) => {
+                state.created_rooms += 1;
+                let room_id = format!("!new{}:{}", state.created_rooms, FAKE_SERVER_NAME);
+                state.joined_rooms.push(room_id.clone());
+                FakeResponse::Json(200, json!({ "room_id": room_id }))
+            },
+            (&reqwest::Method::POST, &["join", room]) => {
+                // Aliases are resolved to a made up room ID on this server
+                let room_id = if room.starts_with('#') {
+                    format!("!{}", &room[1..])
+                } else {
+                    String::from(room)
+                };
+                state.joined_rooms.push(room_id.clone());
+                FakeResponse::Json(200, json!({ "room_id": room_id }))
+            },
+            (&reqwest::Method::POST, &["rooms", room_id, "leave"]) => {
+                state.joined_rooms.retain(|joined| joined != room_id);
+                FakeResponse::Json(200, json!({}))
+            },
+            // Rooms can only be forgotten once they've been left
+            (&reqwest::Method::POST, &["rooms", room_id, "forget"]) => {
+                if state.joined_rooms.iter().any(|joined| joined == room_id) {
+                    FakeResponse::error(400, "M_UNKNOWN", "User is in room")
+                } else {
+                    FakeResponse::Json(200, json!({}))
+                }
+            },
+            (&reqwest::Method::POST, &["rooms", _, "invite"]) |
+            (&reqwest::Method::POST, &["rooms", _, "kick"]) |
+            (&reqwest::Method::POST, &["rooms", _, "ban"]) |
+            (&reqwest::Method::POST, &["rooms", _, "unban"]) => {
+                FakeResponse::Json(200, json!({}))
+            },
+            (&reqwest::Method::PUT, &["rooms", room_id, "send", event_type, txn_id]) => {
+                FakeHomeserver::send_event(state, room_id, event_type, txn_id, None, request)
+            },
+            (&reqwest::Method::PUT, &["rooms", room_id, "redact", event_id, txn_id]) => {
+                FakeHomeserver::send_event(state, room_id, "m.room.redaction", txn_id, Some(event_id), request)
+            },
+            // An empty state key is addressed by leaving it off the path
+            (&reqwest::Method::PUT, &["rooms", room_id, "state", event_type]) => {
+                FakeHomeserver::set_state(state, room_id, event_type, "", request)
+            },
+            (&reqwest::Method::PUT, &["rooms", room_id, "state", event_type, state_key]) => {
+                FakeHomeserver::set_state(state, room_id, event_type, state_key, request)
+            },
+            (&reqwest::Method::GET, &["rooms", room_id, "state", event_type]) => {
+                FakeHomeserver::get_state(state, room_id, event_type, "")
+            },
+            (&reqwest::Method::GET, &["rooms", room_id, "state", event_type, state_key]) => {
+                FakeHomeserver::get_state(state, room_id, event_type, state_key)
+            },
+            (&reqwest::Method::GET, &["rooms", room_id, "state"]) => {
+                FakeResponse::Json(200, json!(state.room_state.get(room_id).cloned().unwrap_or_default()))
+            },
+            (&reqwest::Method::GET, &["rooms", room_id, "messages"]) => {
+                let start = request.query.get("from").cloned().unwrap_or_default();
+                let page = state.message_pages.get_mut(room_id).and_then(|pages| pages.pop_front());
+
+                match page {
+                    Some((chunk, end)) => FakeResponse::Json(200, json!({ "start": start, "end": end, "chunk": chunk })),
+                    None => FakeResponse::Json(200, json!({ "start": start, "end": start, "chunk": [] }))
+                }
+            },
+            // Media is stored as uploaded, and thumbnails are the whole upload
+            (&reqwest::Method::POST, &["upload"]) => {
+                let content_type = request.content_type.clone().unwrap_or_default();
+                state.media.push((content_type, request.raw_body.clone().unwrap_or_default()));
+                FakeResponse::Json(200, json!({ "content_uri": format!("mxc://{}/media{}", FAKE_SERVER_NAME, state.media.len() - 1) }))
+            },
+            (&reqwest::Method::GET, &["download", server_name, media_id]) |
+            (&reqwest::Method::GET, &["thumbnail", server_name, media_id]) => {
+                let media = if server_name == FAKE_SERVER_NAME && media_id.starts_with("media") {
+                    media_id["media".len()..].parse::<usize>().ok().and_then(|i| state.media.get(i))
+                } else {
+                    None
+                };
+
+                match media {
+                    Some(&(ref content_type, ref data)) => FakeResponse::Bytes(content_type.clone(), data.clone()),
+                    None => FakeResponse::error(404, "M_NOT_FOUND", "Not found")
+                }
+            },
+            _ => FakeResponse::error(404, "M_UNRECOGNIZED", "Unrecognized request")
+        }
+    }
+
+    fn login(state: &mut FakeState, request: &RecordedRequest) -> FakeResponse {
+        let body = request.body.clone().unwrap_or(json!({}));
+        let user = body["user"].as_str().unwrap_or("");
+        let localpart = state.user_id.trim_start_matches('@').split(':').next().unwrap_or("").to_string();
+
+        let user_matches = user == state.user_id || user == localpart;
+        if !user_matches || body["password"].as_str() != Some(state.password.as_str()) {
+            return FakeResponse::error(403, "M_FORBIDDEN", "Invalid password");
+        }
+
+        state.logged_in = true;
+
+        FakeResponse::Json(200, json!({
+            "access_token": FAKE_ACCESS_TOKEN,
+            "home_server": FAKE_SERVER_NAME,
+            "user_id": state.user_id,
+            "device_id": FAKE_DEVICE_ID
+        }))
+    }
+
+    fn send_event(state: &mut FakeState, room_id: &str, event_type: &str, txn_id: &str, redacts: Option<&str>, request: &RecordedRequest) -> FakeResponse {
+        // Resending with a transaction ID we've already seen gets back the original event
+        let existing = state.sent_events.iter()
+                            .find(|e| e.room_id == room_id && e.txn_id == txn_id)
+                            .map(|e| e.event_id.clone());
+
+        let event_id = match existing {
+            Some(event_id) => event_id,
+            None => {
+                let event_id = format!("$sent{}:{}", state.sent_events.len(), FAKE_SERVER_NAME);
+                state.sent_events.push(SentEvent {
+                    room_id: String::from(room_id),
+                    event_type: String::from(event_type),
+                    txn_id: String::from(txn_id),
+                    event_id: event_id.clone(),
+                    content: request.body.clone().unwrap_or(json!({})),
+                    redacts: redacts.map(String::from)
+                });
+                event_id
+            }
+        };
+
+        FakeResponse::Json(200, json!({ "event_id": event_id }))
+    }
+
+    // Replaces any state event with the same type and state key
+    fn set_state(state: &mut FakeState, room_id: &str, event_type: &str, state_key: &str, request: &RecordedRequest) -> FakeResponse {
+        let event_id = format!("$state{}:{}", state.room_state.values().map(Vec::len).sum::<usize>(), FAKE_SERVER_NAME);
+        let event = json!({
+            "type": event_type,
+            "state_key": state_key,
+            "event_id": event_id,
+            "sender": state.user_id,
+            "room_id": room_id,
+            "origin_server_ts": 1550000000000i64,
+            "content": request.body.clone().unwrap_or(json!({}))
+        });
+
+        let room_state = state.room_state.entry(String::from(room_id)).or_default();
+        room_state.retain(|e| e["type"] != event_type || e["state_key"] != state_key);
+        room_state.push(event);
+
+        FakeResponse::Json(200, json!({ "event_id": event_id }))
+    }
+
+    fn get_state(state: &FakeState, room_id: &str, event_type: &str, state_key: &str) -> FakeResponse {
+        let event = state.room_state.get(room_id)
+                         .and_then(|room_state| room_state.iter().find(|e| e["type"] == event_type && e["state_key"] == state_key));
+
+        match event {
+            Some(event) => FakeResponse::Json(200, event["content"].clone()),
+            None => FakeResponse::error(404, "M_NOT_FOUND", "Event not found")
+        }
+    }
+
+    fn respond(response: FakeResponse, timeouts: &Timeouts) -> Result<HttpResponse, MatrixClientError> {
+        match response {
+            FakeResponse::Json(status, body) => Ok(HttpResponse {
+                status: reqwest::StatusCode::from_u16(status).expect("bad status in fake response"),
+                content_type: Some(String::from("application/json")),
+                body: serde_json::to_vec(&body).expect("fake response isn't serializable")
+            }),
+            FakeResponse::Bytes(content_type, data) => Ok(HttpResponse {
+                status: reqwest::StatusCode::OK,
+                content_type: Some(content_type),
+                body: data
+            }),
+            FakeResponse::Timeout => Err(MatrixClientError::Timeout),
+            FakeResponse::Slow(delay, response) => {
+                match timeouts.request_timeout() {
+                    Some(request_timeout) if request_timeout < delay => {
+                        thread::sleep(request_timeout);
+                        Err(MatrixClientError::Timeout)
+                    },
+                    _ => {
+                        thread::sleep(delay);
+                        FakeHomeserver::respond(*response, timeouts)
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl HttpTransport for FakeHomeserver {
+    fn send(&self, request: &MatrixRequest, timeouts: &Timeouts) -> Result<HttpResponse, MatrixClientError> {
+        let recorded = FakeHomeserver::record(request, timeouts);
+
+        let response = {
+            let mut state = self.state();
+            state.requests.push(recorded.clone());
+
+            match FakeHomeserver::take_scripted(&mut state, &recorded) {
+                Some(response) => response,
+                None => FakeHomeserver::handle(&mut state, &recorded)
+            }
+        };
+
+        FakeHomeserver::respond(response, timeouts)
+    }
+}
+
+// Answers straight away, on the calling thread
+impl AsyncHttpTransport for FakeHomeserver {
+    fn send(&self, request: &MatrixRequest, timeouts: &Timeouts) -> Box<dyn Future<Item = HttpResponse, Error = MatrixClientError> + Send> {
+        Box::new(future::result(HttpTransport::send(self, request, timeouts)))
+    }
+}
@@ -0,0 +1,26 @@
+#[macro_use]
+extern crate serde_derive;
+
+extern crate chrono;
+extern crate futures;
+extern crate pulldown_cmark;
+extern crate reqwest;
+extern crate serde;
+extern crate serde_json;
+extern crate tokio;
+
+#[cfg(test)]
+extern crate url;
+
+#[cfg(test)]
+mod fake_homeserver;
+pub mod matrix_async_client;
+pub mod matrix_bot;
+pub mod matrix_client;
+pub mod matrix_core;
+pub mod matrix_error;
+pub mod matrix_html;
+pub mod matrix_identifiers;
+pub mod matrix_sync_token;
+pub mod matrix_transport;
+pub mod matrix_url;
@@ -1,15 +1,7 @@
-#[macro_use]
-extern crate serde_derive;
-
-extern crate chrono;
 extern crate clap;
-extern crate reqwest;
-extern crate serde_json;
-
-mod matrix_bot;
-mod matrix_client;
+extern crate jacobian;
 
-use matrix_bot::*;
+use jacobian::matrix_bot::*;
 use clap::{Arg, App};
 
 fn main() {
@@ -35,12 +27,49 @@ fn main() {
                                .required(true)
                                .help("The server to login to")
                                .takes_value(true))
+                          .arg(Arg::with_name("reply-style")
+                               .long("reply-style")
+                               .possible_values(&["room", "reply", "thread"])
+                               .default_value("room")
+                               .help("Whether to answer commands in the room, as replies or in threads")
+                               .takes_value(true))
+                          .arg(Arg::with_name("sync-token-file")
+                               .long("sync-token-file")
+                               .help("Where to keep the token for resuming syncs (default: <username>.sync_token)")
+                               .takes_value(true))
+                          .arg(Arg::with_name("backlog")
+                               .long("backlog")
+                               .possible_values(&["catch-up", "skip"])
+                               .default_value("catch-up")
+                               .help("Whether to act on events sent while the bot wasn't running")
+                               .takes_value(true))
+                          .arg(Arg::with_name("react")
+                               .long("react")
+                               .help("Acknowledge commands with a reaction"))
                           .get_matches();
 
     let mut bot = MatrixBot::new(
             matches.value_of("server").unwrap(),
             matches.value_of("username").unwrap(),
             matches.value_of("password").unwrap());
+
+    bot.set_reply_style(match matches.value_of("reply-style") {
+        Some("reply") => ReplyStyle::Reply,
+        Some("thread") => ReplyStyle::Thread,
+        _ => ReplyStyle::InRoom
+    });
+    bot.set_acknowledge_commands(matches.is_present("react"));
+
+    match matches.value_of("sync-token-file") {
+        Some(path) => bot.set_sync_token_store(Box::new(FileSyncTokenStore::new(path))),
+        None => ()
+    };
+
+    bot.set_backlog(match matches.value_of("backlog") {
+        Some("skip") => Backlog::Skip,
+        _ => Backlog::CatchUp
+    });
+
     bot.run();
 }
 
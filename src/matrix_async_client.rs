@@ -0,0 +1,668 @@
+extern crate futures;
+extern crate tokio;
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::Instant;
+
+use futures::{future, Async, Future, Poll, Stream};
+use futures::future::{Either, Loop};
+use tokio::timer::Delay;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use matrix_client::*;
+use matrix_core::*;
+use matrix_transport::*;
+
+pub type MatrixFuture<T> = Box<dyn Future<Item = T, Error = MatrixClientError> + Send>;
+
+// The same client as MatrixClient, but every call returns a future instead of blocking the
+// calling thread. Requests are built and responses parsed by the same ClientCore, so the two
+// only differ in how the bytes get to the homeserver and back. Must be driven by a tokio
+// runtime. Clones share their login state, so one logged in client can be handed to many tasks.
+#[derive(Clone)]
+pub struct AsyncMatrixClient {
+    core: Arc<RwLock<ClientCore>>,
+    transport: Arc<dyn AsyncHttpTransport>,
+    timeouts: Timeouts,
+    timeouts_override: Option<Timeouts>,
+    retry_policy: RetryPolicy,
+    txn_ids: Arc<TransactionIds>
+}
+
+impl AsyncMatrixClient {
+    pub fn new(homeserver: &str, device_id: Option<String>) -> AsyncMatrixClient {
+        AsyncMatrixClient::with_transport(homeserver, device_id, Box::new(ReqwestAsyncTransport::new()))
+    }
+
+    pub fn with_transport(homeserver: &str, device_id: Option<String>, transport: Box<dyn AsyncHttpTransport>) -> AsyncMatrixClient {
+        AsyncMatrixClient {
+            core: Arc::new(RwLock::new(ClientCore::new(homeserver, device_id))),
+            transport: Arc::from(transport),
+            timeouts: Timeouts::default(),
+            timeouts_override: None,
+            retry_policy: RetryPolicy::default(),
+            txn_ids: Arc::new(TransactionIds::new())
+        }
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) -> () {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn get_retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) -> () {
+        self.timeouts = timeouts;
+    }
+
+    pub fn get_timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    // Runs `call` with `timeouts` used in place of the client's own for every request it makes.
+    // Requests are built when a call is made, so the futures `call` returns keep the timeouts
+    // after it has finished.
+    pub fn with_timeouts<T, F>(&mut self, timeouts: Timeouts, call: F) -> T
+        where F: FnOnce(&mut AsyncMatrixClient) -> T {
+        let previous = self.timeouts_override.take();
+        self.timeouts_override = Some(timeouts);
+        let result = call(self);
+        self.timeouts_override = previous;
+        result
+    }
+
+    fn effective_timeouts(&self) -> Timeouts {
+        self.timeouts_override.unwrap_or(self.timeouts)
+    }
+
+    fn core(&self) -> RwLockReadGuard<'_, ClientCore> {
+        self.core.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Sends the request, sending it again for as long as the retry policy and the total timeout allow
+    fn execute(&self, request: Result<MatrixRequest, MatrixClientError>) -> MatrixFuture<HttpResponse> {
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => { return Box::new(future::err(e)); }
+        };
+
+        let timeouts = request.timeouts.unwrap_or(self.effective_timeouts());
+        let deadline = timeouts.total.map(|total| Instant::now() + total);
+        let retry_policy = self.retry_policy.clone();
+        let transport = self.transport.clone();
+
+        Box::new(future::loop_fn(0, move |attempt| {
+            let retry_policy = retry_policy.clone();
+            let idempotency = request.idempotency;
+
+            let attempt_timeouts = match timeouts_for_attempt(&timeouts, deadline) {
+                Some(attempt_timeouts) => attempt_timeouts,
+                None => { return Either::A(future::err(MatrixClientError::Timeout)); }
+            };
+
+            Either::B(transport.send(&request, &attempt_timeouts).then(move |result| {
+                let delay = match result {
+                    Ok(response) => {
+                        match retry_policy.delay_for_response(&response, idempotency, attempt) {
+                            Some(delay) if fits_before_deadline(deadline, delay) => delay,
+                            _ => { return Either::A(future::ok(Loop::Break(response))); }
+                        }
+                    },
+                    Err(e) => {
+                        match retry_policy.delay_for_error(&e, idempotency, attempt) {
+                            Some(delay) if fits_before_deadline(deadline, delay) => delay,
+                            _ => { return Either::A(future::err(e)); }
+                        }
+                    }
+                };
+
+                Either::B(Delay::new(Instant::now() + delay)
+                    .map_err(|e| MatrixClientError::Io(io::Error::new(io::ErrorKind::Other, e)))
+                    .map(move |_| Loop::Continue(attempt + 1)))
+            }))
+        }))
+    }
+
+    fn execute_json<T: DeserializeOwned + Send + 'static>(&self, request: Result<MatrixRequest, MatrixClientError>) -> MatrixFuture<T> {
+        Box::new(self.execute(request).and_then(|response| parse_json_response(&response)))
+    }
+
+    fn execute_empty(&self, request: Result<MatrixRequest, MatrixClientError>) -> MatrixFuture<()> {
+        Box::new(self.execute(request).and_then(|response| check_response(&response)))
+    }
+
+    fn execute_media(&self, request: Result<MatrixRequest, MatrixClientError>) -> MatrixFuture<MediaResponse> {
+        Box::new(self.execute(request).and_then(parse_media_response))
+    }
+
+    pub fn get_supported_versions(&self) -> MatrixFuture<VersionResponse> {
+        let request = self.core().get_supported_versions_request();
+        self.execute_json(request)
+    }
+
+    pub fn login(&self, user: &str, password: &str) -> MatrixFuture<LoginResponse> {
+        let request = self.core().login_request(user, password);
+        let core = self.core.clone();
+
+        Box::new(self.execute(request).and_then(move |response| {
+            let login_response: LoginResponse = parse_json_response(&response)?;
+
+            core.write().unwrap_or_else(|e| e.into_inner()).apply_login(&login_response);
+
+            Ok(login_response)
+        }))
+    }
+
+    pub fn logout(&self) -> MatrixFuture<()> {
+        let request = self.core().logout_request();
+        self.execute_logout(request)
+    }
+
+    pub fn logout_all(&self) -> MatrixFuture<()> {
+        let request = self.core().logout_all_request();
+        self.execute_logout(request)
+    }
+
+    fn execute_logout(&self, request: Result<MatrixRequest, MatrixClientError>) -> MatrixFuture<()> {
+        let core = self.core.clone();
+
+        Box::new(self.execute(request).and_then(move |response| {
+            check_response(&response)?;
+
+            core.write().unwrap_or_else(|e| e.into_inner()).apply_logout();
+
+            Ok(())
+        }))
+    }
+
+    pub fn list_public_rooms(&self) -> MatrixFuture<PublicRoomsResponse> {
+        let request = self.core().list_public_rooms_request();
+        self.execute_json(request)
+    }
+
+    pub fn join_room(&self, room_id_or_alias: &RoomIdOrAliasId) -> MatrixFuture<JoinResponse> {
+        let request = self.core().join_room_request(room_id_or_alias);
+        self.execute_json(request)
+    }
+
+    pub fn create_filter(&self, filter: &Filter) -> MatrixFuture<CreateFilterResponse> {
+        let request = self.core().create_filter_request(filter);
+        self.execute_json(request)
+    }
+
+    pub fn get_filter(&self, filter_id: &str) -> MatrixFuture<Filter> {
+        let request = self.core().get_filter_request(filter_id);
+        self.execute_json(request)
+    }
+
+    pub fn create_room(&self, create_room_request: &CreateRoomRequest) -> MatrixFuture<CreateRoomResponse> {
+        let request = self.core().create_room_request(create_room_request);
+        self.execute_json(request)
+    }
+
+    pub fn leave_room(&self, room: &RoomId) -> MatrixFuture<()> {
+        let request = self.core().room_membership_request(room, "leave", &EmptyRequest {});
+        self.execute_empty(request)
+    }
+
+    // Forgetting is only allowed once we have left the room
+    pub fn forget_room(&self, room: &RoomId) -> MatrixFuture<()> {
+        let request = self.core().room_membership_request(room, "forget", &EmptyRequest {});
+        self.execute_empty(request)
+    }
+
+    pub fn invite_user(&self, room: &RoomId, user_id: &UserId) -> MatrixFuture<()> {
+        let request = self.core().room_membership_for_user_request(room, "invite", user_id, None);
+        self.execute_empty(request)
+    }
+
+    pub fn kick_user(&self, room: &RoomId, user_id: &UserId, reason: Option<&str>) -> MatrixFuture<()> {
+        let request = self.core().room_membership_for_user_request(room, "kick", user_id, reason);
+        self.execute_empty(request)
+    }
+
+    pub fn ban_user(&self, room: &RoomId, user_id: &UserId, reason: Option<&str>) -> MatrixFuture<()> {
+        let request = self.core().room_membership_for_user_request(room, "ban", user_id, reason);
+        self.execute_empty(request)
+    }
+
+    pub fn unban_user(&self, room: &RoomId, user_id: &UserId) -> MatrixFuture<()> {
+        let request = self.core().room_membership_for_user_request(room, "unban", user_id, None);
+        self.execute_empty(request)
+    }
+
+    pub fn sync(&self, filter: Option<&SyncFilter>, since: Option<&String>, full_state: Option<bool>, timeout_ms: Option<u64>) -> MatrixFuture<SyncResponse> {
+        let request = self.core().sync_request(filter, since, full_state, timeout_ms, self.effective_timeouts());
+        self.execute_json(request)
+    }
+
+    pub fn room_messages(&self, room: &RoomId, from: &str, to: Option<&str>, dir: Direction, limit: Option<u64>, filter: Option<&RoomEventFilter>) -> MatrixFuture<RoomMessagesResponse> {
+        let request = self.core().room_messages_request(room, from, to, dir, limit, filter);
+        self.execute_json(request)
+    }
+
+    // The async version of MatrixClient::room_messages_iter: a stream of a room's events, fetched
+    // a page at a time as the stream is polled
+    pub fn room_messages_stream(&self, room: &RoomId, from: &str, to: Option<&str>, dir: Direction, limit: Option<u64>, filter: Option<RoomEventFilter>) -> RoomMessagesStream {
+        RoomMessagesStream {
+            client: self.clone(),
+            room: room.clone(),
+            from: Some(String::from(from)),
+            to: to.map(String::from),
+            dir: dir,
+            limit: limit,
+            filter: filter,
+            events: VecDeque::new(),
+            page: None
+        }
+    }
+
+    pub fn send_state_event<T: Serialize>(&self, room: &RoomId, event_type: &str, state_key: &str, content: &T) -> MatrixFuture<SendEventResponse> {
+        let request = self.core().send_state_event_request(room, event_type, state_key, content);
+        self.execute_json(request)
+    }
+
+    pub fn get_state_event<T: DeserializeOwned + Send + 'static>(&self, room: &RoomId, event_type: &str, state_key: &str) -> MatrixFuture<T> {
+        let request = self.core().get_state_event_request(room, event_type, state_key);
+        self.execute_json(request)
+    }
+
+    pub fn get_room_state(&self, room: &RoomId) -> MatrixFuture<Vec<Event>> {
+        let request = self.core().get_room_state_request(room);
+        self.execute_json(request)
+    }
+
+    pub fn upload_bytes(&self, data: Vec<u8>, content_type: &str, filename: Option<&str>) -> MatrixFuture<UploadResponse> {
+        let request = self.core().upload_request(data, content_type, filename);
+        self.execute_json(request)
+    }
+
+    // The reader is read to the end before anything is sent, blocking until it has been
+    pub fn upload<R: Read>(&self, mut reader: R, content_type: &str, filename: Option<&str>) -> MatrixFuture<UploadResponse> {
+        let mut data = Vec::new();
+        match reader.read_to_end(&mut data) {
+            Ok(_) => self.upload_bytes(data, content_type, filename),
+            Err(e) => Box::new(future::err(MatrixClientError::Io(e)))
+        }
+    }
+
+    pub fn download(&self, mxc: &MxcUri) -> MatrixFuture<MediaResponse> {
+        let request = self.core().download_request(mxc);
+        self.execute_media(request)
+    }
+
+    pub fn thumbnail(&self, mxc: &MxcUri, width: u64, height: u64, method: ThumbnailMethod) -> MatrixFuture<MediaResponse> {
+        let request = self.core().thumbnail_request(mxc, width, height, method);
+        self.execute_media(request)
+    }
+
+    fn upload_file(&self, path: &Path) -> MatrixFuture<UploadedFile> {
+        let file = match FileUpload::read(path) {
+            Ok(file) => file,
+            Err(e) => { return Box::new(future::err(e)); }
+        };
+
+        let size = file.data.len() as i64;
+        let filename = file.filename;
+        let content_type = file.content_type;
+
+        let upload = self.upload_bytes(file.data, content_type, Some(filename.as_str()));
+
+        Box::new(upload.map(move |upload_response| UploadedFile {
+            filename: filename,
+            content_type: content_type,
+            size: size,
+            content_uri: upload_response.content_uri
+        }))
+    }
+
+    pub fn image_message_from_file<P: AsRef<Path>>(&self, path: P) -> MatrixFuture<ImageMessageType> {
+        Box::new(self.upload_file(path.as_ref()).map(UploadedFile::into_image_message))
+    }
+
+    pub fn file_message_from_file<P: AsRef<Path>>(&self, path: P) -> MatrixFuture<FileMessageType> {
+        Box::new(self.upload_file(path.as_ref()).map(UploadedFile::into_file_message))
+    }
+
+    pub fn send_image_file<P: AsRef<Path>>(&self, room: &RoomId, path: P) -> MatrixFuture<SendEventResponse> {
+        let client = self.clone();
+        let room = room.clone();
+
+        Box::new(self.image_message_from_file(path).and_then(move |image_message| {
+            client.send_room_message(&room, &RoomMessageTypes::ImageMessage(image_message))
+        }))
+    }
+
+    pub fn send_file<P: AsRef<Path>>(&self, room: &RoomId, path: P) -> MatrixFuture<SendEventResponse> {
+        let client = self.clone();
+        let room = room.clone();
+
+        Box::new(self.file_message_from_file(path).and_then(move |file_message| {
+            client.send_room_message(&room, &RoomMessageTypes::FileMessage(file_message))
+        }))
+    }
+
+    pub fn new_transaction_id(&self) -> String {
+        self.txn_ids.next()
+    }
+
+    pub fn send_room_event_with_txn_id<T: Serialize>(&self, room: &RoomId, event_type: &str, content: &T, txn_id: &str) -> MatrixFuture<SendEventResponse> {
+        let request = self.core().send_room_event_request(room, event_type, content, txn_id);
+        self.execute_json(request)
+    }
+
+    pub fn send_room_event<T: Serialize>(&self, room: &RoomId, event_type: &str, content: &T) -> MatrixFuture<SendEventResponse> {
+        let txn_id = self.new_transaction_id();
+        self.send_room_event_with_txn_id(room, event_type, content, txn_id.as_str())
+    }
+
+    pub fn send_room_message_with_txn_id(&self, room: &RoomId, message: &RoomMessageTypes, txn_id: &str) -> MatrixFuture<SendEventResponse> {
+        self.send_room_event_with_txn_id(room, ROOM_MESSAGE_EVENT_TYPE, message, txn_id)
+    }
+
+    pub fn send_room_message(&self, room: &RoomId, message: &RoomMessageTypes) -> MatrixFuture<SendEventResponse> {
+        self.send_room_event(room, ROOM_MESSAGE_EVENT_TYPE, message)
+    }
+
+    pub fn send_markdown_message(&self, room: &RoomId, markdown: &str) -> MatrixFuture<SendEventResponse> {
+        self.send_room_message(room, &RoomMessageTypes::TextMessage(TextMessageType::markdown(markdown)))
+    }
+
+    pub fn send_markdown_notice(&self, room: &RoomId, markdown: &str) -> MatrixFuture<SendEventResponse> {
+        self.send_room_message(room, &RoomMessageTypes::NoticeMessage(NoticeMessageType::markdown(markdown)))
+    }
+
+    pub fn send_reply(&self, room: &RoomId, original: &EventContainer<RoomMessageOptionType>, message: RoomMessageTypes) -> MatrixFuture<SendEventResponse> {
+        match message.into_reply(room, original) {
+            Ok(reply) => self.send_room_message(room, &reply),
+            Err(e) => Box::new(future::err(e))
+        }
+    }
+
+    pub fn send_thread_message(&self, room: &RoomId, thread_root: &EventId, latest_event: &EventId, message: RoomMessageTypes) -> MatrixFuture<SendEventResponse> {
+        self.send_room_message(room, &message.into_thread_message(thread_root, latest_event))
+    }
+
+    pub fn redact_with_txn_id(&self, room: &RoomId, event_id: &EventId, reason: Option<&str>, txn_id: &str) -> MatrixFuture<SendEventResponse> {
+        let request = self.core().redact_request(room, event_id, reason, txn_id);
+        self.execute_json(request)
+    }
+
+    pub fn redact(&self, room: &RoomId, event_id: &EventId, reason: Option<&str>) -> MatrixFuture<SendEventResponse> {
+        let txn_id = self.new_transaction_id();
+        self.redact_with_txn_id(room, event_id, reason, txn_id.as_str())
+    }
+
+    pub fn react(&self, room: &RoomId, event_id: &EventId, key: &str) -> MatrixFuture<SendEventResponse> {
+        self.send_room_event(room, REACTION_EVENT_TYPE, &ReactionEvent::new(event_id, key))
+    }
+
+    pub fn send_edit(&self, room: &RoomId, original: &EventId, new_content: RoomMessageTypes) -> MatrixFuture<SendEventResponse> {
+        self.send_room_message(room, &new_content.into_edit(original))
+    }
+}
+
+pub struct RoomMessagesStream {
+    client: AsyncMatrixClient,
+    room: RoomId,
+    from: Option<String>,
+    to: Option<String>,
+    dir: Direction,
+    limit: Option<u64>,
+    filter: Option<RoomEventFilter>,
+    events: VecDeque<Event>,
+
+    // The page being fetched, and the token it's being fetched from
+    page: Option<(String, MatrixFuture<RoomMessagesResponse>)>
+}
+
+impl RoomMessagesStream {
+    // The pagination token to resume from once the buffered events are used up
+    pub fn next_token(&self) -> Option<&String> {
+        self.from.as_ref()
+    }
+}
+
+impl Stream for RoomMessagesStream {
+    type Item = Event;
+    type Error = MatrixClientError;
+
+    fn poll(&mut self) -> Poll<Option<Event>, MatrixClientError> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Async::Ready(Some(event)));
+            }
+
+            let (from, page) = match self.page.take() {
+                Some((from, mut page)) => match page.poll()? {
+                    Async::Ready(page) => (from, page),
+                    Async::NotReady => {
+                        self.page = Some((from, page));
+                        return Ok(Async::NotReady);
+                    }
+                },
+                None => {
+                    let from = match self.from.take() {
+                        Some(x) => x,
+                        None => { return Ok(Async::Ready(None)); }
+                    };
+
+                    let page = self.client.room_messages(&self.room, from.as_str(), self.to.as_ref().map(String::as_str), self.dir, self.limit, self.filter.as_ref());
+                    self.page = Some((from, page));
+                    continue;
+                }
+            };
+
+            if page.chunk.is_empty() {
+                return Ok(Async::Ready(None));
+            }
+
+            // A missing or unchanged end token means this was the last page
+            match page.end {
+                Some(end) => {
+                    if end != from {
+                        self.from = Some(end);
+                    }
+                },
+                None => ()
+            };
+
+            self.events.extend(page.chunk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::Duration;
+    use serde_json::json;
+    use tokio::runtime::current_thread::Runtime;
+    use fake_homeserver::*;
+
+    const ROOM: &'static str = "!room:fake.example.org";
+
+    fn room() -> RoomId {
+        RoomId::parse(ROOM).unwrap()
+    }
+
+    // Retries wait on tokio timers, so futures are driven by a runtime rather than wait()
+    fn run<F: Future>(future: F) -> Result<F::Item, F::Error> {
+        Runtime::new().unwrap().block_on(future)
+    }
+
+    fn uploaded(homeserver: &FakeHomeserver) -> () {
+        homeserver.respond_with(reqwest::Method::POST, "upload", FakeResponse::Json(200, json!({ "content_uri": "mxc://fake.example.org/abc" })));
+    }
+
+    #[test]
+    fn requests_go_through_the_transport() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_async_client();
+
+        run(client.send_markdown_message(&room(), "hello")).unwrap();
+
+        let sent = homeserver.sent_events();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].room_id, ROOM);
+        assert_eq!(sent[0].content["body"], "hello");
+        assert_eq!(homeserver.requests_to("rooms")[0].access_token, Some(String::from("fake_access_token")));
+    }
+
+    #[test]
+    fn rate_limited_requests_wait_and_retry() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_async_client();
+        homeserver.respond_with(reqwest::Method::PUT, "rooms", FakeResponse::rate_limited(10));
+
+        run(client.send_markdown_message(&room(), "hello")).unwrap();
+
+        let sends = homeserver.requests_to("rooms");
+        assert_eq!(sends.len(), 2);
+        assert_eq!(sends[0].path, sends[1].path);
+        assert_eq!(homeserver.sent_events().len(), 1);
+    }
+
+    #[test]
+    fn timeouts_can_be_overridden_for_one_call() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_async_client();
+        let timeouts = Timeouts {
+            connect: Some(Duration::from_secs(1)),
+            read: Some(Duration::from_secs(2)),
+            total: None
+        };
+
+        let overridden = client.with_timeouts(timeouts, |client| client.get_supported_versions());
+        run(overridden.join(client.get_supported_versions())).unwrap();
+
+        let requests = homeserver.requests_to("versions");
+        assert_eq!(requests[0].timeouts, timeouts);
+        assert_eq!(requests[1].timeouts, Timeouts::default());
+    }
+
+    #[test]
+    fn attempts_are_cut_short_by_the_total_timeout() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_async_client();
+        client.set_timeouts(Timeouts {
+            total: Some(Duration::from_millis(100)),
+            .. Timeouts::default()
+        });
+        homeserver.respond_with(reqwest::Method::GET, "versions", FakeResponse::slow(Duration::from_secs(1), FakeResponse::Json(200, json!({ "versions": [] }))));
+
+        match run(client.get_supported_versions()) {
+            Err(MatrixClientError::Timeout) => (),
+            other => panic!("expected Timeout, got {:?}", other)
+        };
+
+        assert!(homeserver.requests_to("versions")[0].timeouts.request_timeout().unwrap() <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn uploads_read_the_whole_reader() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_async_client();
+        uploaded(&homeserver);
+
+        let response = run(client.upload(&b"some bytes"[..], "text/plain", Some("notes.txt"))).unwrap();
+
+        assert_eq!(response.content_uri.to_string(), "mxc://fake.example.org/abc");
+        let upload = &homeserver.requests_to("upload")[0];
+        assert_eq!(upload.query.get("filename"), Some(&String::from("notes.txt")));
+        assert_eq!(upload.content_type, Some(String::from("text/plain")));
+        assert_eq!(upload.raw_body, Some(b"some bytes".to_vec()));
+    }
+
+    #[test]
+    fn files_are_uploaded_then_sent() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_async_client();
+        let path = env::temp_dir().join(format!("jacobian-test-{}-cat.png", process::id()));
+        fs::write(&path, b"not really a png").unwrap();
+        uploaded(&homeserver);
+        uploaded(&homeserver);
+
+        run(client.send_image_file(&room(), &path)).unwrap();
+        run(client.send_file(&room(), &path)).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let uploads = homeserver.requests_to("upload");
+        assert_eq!(uploads.len(), 2);
+        assert_eq!(uploads[0].content_type, Some(String::from("image/png")));
+
+        let sent = homeserver.sent_events();
+        let filename = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(sent[0].content["msgtype"], "m.image");
+        assert_eq!(sent[0].content["url"], "mxc://fake.example.org/abc");
+        assert_eq!(sent[0].content["info"]["size"], 16);
+        assert_eq!(sent[1].content["msgtype"], "m.file");
+        assert_eq!(sent[1].content["filename"], filename);
+    }
+
+    #[test]
+    fn missing_files_fail_without_uploading() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_async_client();
+
+        match run(client.send_file(&room(), "/no/such/file.txt")) {
+            Err(MatrixClientError::Io(_)) => (),
+            other => panic!("expected an Io error, got {:?}", other)
+        };
+        assert!(homeserver.requests_to("upload").is_empty());
+    }
+
+    fn page(start: &str, end: &str, event_ids: &[&str]) -> FakeResponse {
+        let chunk: Vec<serde_json::Value> = event_ids.iter().map(|event_id| json!({
+            "type": ROOM_MESSAGE_EVENT_TYPE,
+            "event_id": event_id,
+            "sender": "@alice:fake.example.org",
+            "origin_server_ts": 1550000000000i64,
+            "content": { "msgtype": "m.text", "body": "hi" }
+        })).collect();
+
+        FakeResponse::Json(200, json!({ "start": start, "end": end, "chunk": chunk }))
+    }
+
+    #[test]
+    fn room_messages_are_streamed_a_page_at_a_time() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_async_client();
+        let messages = format!("rooms/{}/messages", ROOM);
+        homeserver.respond_with(reqwest::Method::GET, messages.as_str(), page("t0", "t1", &["$3", "$2"]));
+        homeserver.respond_with(reqwest::Method::GET, messages.as_str(), page("t1", "t2", &["$1"]));
+        homeserver.respond_with(reqwest::Method::GET, messages.as_str(), page("t2", "t2", &[]));
+
+        let stream = client.room_messages_stream(&room(), "t0", None, Direction::Backward, Some(2), None);
+        let events = run(stream.collect()).unwrap();
+
+        let event_ids: Vec<&str> = events.iter().filter_map(Event::event_id).collect();
+        assert_eq!(event_ids, vec!["$3", "$2", "$1"]);
+        let froms: Vec<Option<String>> = homeserver.requests_to("rooms").iter().map(|r| r.query.get("from").cloned()).collect();
+        assert_eq!(froms, vec![Some(String::from("t0")), Some(String::from("t1")), Some(String::from("t2"))]);
+    }
+
+    #[test]
+    fn room_message_streams_end_after_an_error() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_async_client();
+        homeserver.respond_with(reqwest::Method::GET, "rooms", FakeResponse::error(403, "M_FORBIDDEN", "Not in the room"));
+
+        let stream = client.room_messages_stream(&room(), "t0", None, Direction::Backward, None, None);
+        let results: Vec<Result<Event, MatrixClientError>> = stream.then(Ok::<_, ()>).collect().wait().unwrap();
+
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+    }
+}
@@ -1,22 +1,117 @@
+use std::thread;
+
 use matrix_client::*;
+pub use matrix_sync_token::{FileSyncTokenStore, MemorySyncTokenStore, SyncTokenStore};
+
+const GAP_PAGE_SIZE: u64 = 50;
+
+const COMMAND_DONE: &'static str = "\u{2705}";
+const COMMAND_FAILED: &'static str = "\u{274c}";
+
+// Whether a failed sync means the homeserver won't accept the since token we gave it, rather than
+// being down, rate limiting us or not knowing who we are, in which case the token is still good
+fn is_refused_sync_token(e: &MatrixError) -> bool {
+    e.status.is_client_error()
+        && e.status != reqwest::StatusCode::UNAUTHORIZED
+        && !e.is_rate_limited()
+        && (e.is(ErrorCode::Unknown) || e.is(ErrorCode::InvalidParam))
+}
+
+// Called with every reaction which arrives in a joined room
+pub type ReactionHandler = Box<dyn FnMut(&RoomId, &EventContainer<ReactionEvent>)>;
+
+// Called with every redaction which arrives in a joined room, so anything kept about the redacted
+// event can be deleted too. Events in the same sync have already been redacted by then.
+pub type RedactionHandler = Box<dyn FnMut(&RoomId, &EventContainer<RoomRedactionEvent>)>;
+
+// How the bot answers a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplyStyle {
+    // A plain message in the room
+    InRoom,
+
+    // A rich reply to the command
+    Reply,
+
+    // A message in the command's thread, starting one from the command if it isn't in one
+    Thread
+}
+
+// What to do with events sent while the bot wasn't running
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backlog {
+    // Carry on from the saved sync token, acting on everything missed
+    CatchUp,
+
+    // Start from scratch, ignoring anything sent before the bot started
+    Skip
+}
 
 pub struct MatrixBot {
     matrix_client: MatrixClient,
     username: String,
     password: String,
+    reply_style: ReplyStyle,
+    acknowledge_commands: bool,
+    reaction_handlers: Vec<ReactionHandler>,
+    redaction_handlers: Vec<RedactionHandler>,
+    sync_token_store: Box<dyn SyncTokenStore>,
+    backlog: Backlog,
     should_quit: bool
 }
 
 impl MatrixBot {
+    // Keeps its sync token in a file named after the user in the working directory
     pub fn new(homeserver: &str, username: &str, password: &str) -> MatrixBot {
+        let mut bot = MatrixBot::with_client(MatrixClient::new(homeserver, None), username, password);
+        bot.set_sync_token_store(Box::new(FileSyncTokenStore::new(FileSyncTokenStore::default_path(username))));
+        bot
+    }
+
+    pub fn with_client(matrix_client: MatrixClient, username: &str, password: &str) -> MatrixBot {
         MatrixBot {
-            matrix_client: MatrixClient::new(homeserver, None),
+            matrix_client: matrix_client,
             username: String::from(username),
             password: String::from(password),
+            reply_style: ReplyStyle::InRoom,
+            acknowledge_commands: false,
+            reaction_handlers: Vec::new(),
+            redaction_handlers: Vec::new(),
+            sync_token_store: Box::new(MemorySyncTokenStore::default()),
+            backlog: Backlog::CatchUp,
             should_quit: false
         }
     }
 
+    pub fn set_reply_style(&mut self, reply_style: ReplyStyle) -> () {
+        self.reply_style = reply_style;
+    }
+
+    // React to each command with a check mark once it's been carried out, or a cross if it
+    // couldn't be. This is best-effort: a reaction which can't be sent is logged and not retried,
+    // and the bot carries on with the next command.
+    pub fn set_acknowledge_commands(&mut self, acknowledge_commands: bool) -> () {
+        self.acknowledge_commands = acknowledge_commands;
+    }
+
+    pub fn add_reaction_handler<F>(&mut self, handler: F) -> ()
+        where F: FnMut(&RoomId, &EventContainer<ReactionEvent>) + 'static {
+        self.reaction_handlers.push(Box::new(handler));
+    }
+
+    pub fn set_sync_token_store(&mut self, sync_token_store: Box<dyn SyncTokenStore>) -> () {
+        self.sync_token_store = sync_token_store;
+    }
+
+    pub fn set_backlog(&mut self, backlog: Backlog) -> () {
+        self.backlog = backlog;
+    }
+
+    pub fn add_redaction_handler<F>(&mut self, handler: F) -> ()
+        where F: FnMut(&RoomId, &EventContainer<RoomRedactionEvent>) + 'static {
+        self.redaction_handlers.push(Box::new(handler));
+    }
+
     pub fn run(&mut self) -> () {
         let login = match self.matrix_client.login(self.username.as_ref(), self.password.as_ref()) {
             Ok(x) => x,
@@ -29,8 +124,27 @@ impl MatrixBot {
 
         println!("Logged in! Got: {:#?}", login);
 
-        println!("Attempting initial sync!");
-        let mut next_batch = None;
+        let mut next_batch = match self.backlog {
+            Backlog::CatchUp => self.load_sync_token(),
+            Backlog::Skip => None
+        };
+
+        // Until a sync using it succeeds, the saved token may turn out to be one the homeserver
+        // no longer accepts
+        let mut using_saved_token = next_batch.is_some();
+
+        // A saved token the homeserver refused, which the initial sync then catches up to as
+        // best it can
+        let mut refused_token: Option<String> = None;
+
+        let mut sync_timeouts = 0;
+
+        if next_batch.is_some() {
+            println!("Catching up from the last sync we saw!");
+        } else {
+            println!("Attempting initial sync!");
+        }
+
         loop {
             println!("Using batch {:?}", next_batch);
             let sync_response = match self.matrix_client.sync(None, next_batch.as_ref(), Some(false), Some(30000)) {
@@ -38,19 +152,64 @@ impl MatrixBot {
                     println!("Got a sync!");
                     x
                 },
+                Err(MatrixClientError::Timeout) => {
+                    // Backed off the same way as any other transient failure, so a homeserver
+                    // which has stopped answering isn't hammered with syncs
+                    let retry_policy = self.matrix_client.get_retry_policy();
+                    if sync_timeouts >= retry_policy.max_retries {
+                        println!("Sync timed out {} times in a row! Giving up.", sync_timeouts + 1);
+                        break
+                    }
+
+                    let delay = retry_policy.backoff(sync_timeouts);
+                    sync_timeouts += 1;
+                    println!("Sync timed out! Attempting another in {:?}...", delay);
+                    thread::sleep(delay);
+                    continue
+                },
+                Err(MatrixClientError::Matrix(ref e)) if using_saved_token && is_refused_sync_token(e) => {
+                    println!("Homeserver refused the saved sync token: {}", e);
+                    println!("Forgetting it and attempting initial sync!");
+                    match self.sync_token_store.clear() {
+                        Ok(()) => (),
+                        Err(e) => {
+                            println!("Failed to clear the sync token!");
+                            println!("{:?}", e);
+                        }
+                    };
+                    using_saved_token = false;
+                    refused_token = next_batch.take();
+                    continue
+                },
                 Err(e) => {
                     println!("Got error attempting sync: {:#?}", e);
                     break
                 }
             };
 
-            if next_batch.is_some() {
-                self.process_sync(&sync_response)
+            using_saved_token = false;
+            sync_timeouts = 0;
+            let since = next_batch;
+            next_batch = sync_response.next_batch.clone();
+
+            if let Some(ref since) = since {
+                self.process_sync(sync_response, since)
+            } else if let Some(refused) = refused_token.take() {
+                // The initial sync's timelines are the latest events in each room. Anything sent
+                // before those while we weren't running is fetched the same way as a gap in a
+                // limited timeline, for as far back as the homeserver still understands the old
+                // token.
+                println!("Catching up on the initial sync's timelines instead of the backlog since the refused token!");
+                self.process_sync(sync_response, &refused)
             } else {
                 println!("Not processing initial sync...");
             };
 
-            next_batch = sync_response.next_batch;
+            match next_batch {
+                Some(ref next_batch) => self.save_sync_token(next_batch),
+                None => ()
+            };
+
             if !self.should_quit && next_batch.is_some() {
                 println!("Got a sync! Attempting another...");
             } else if next_batch.is_none() {
@@ -74,58 +233,685 @@ impl MatrixBot {
 
     }
 
-    fn process_sync(&mut self, sync: &SyncResponse) -> () {
-        for (room_name, room_data) in sync.rooms.join.iter() {
-            self.process_joined_room(room_name, room_data);
+    fn load_sync_token(&self) -> Option<String> {
+        match self.sync_token_store.load() {
+            Ok(token) => token,
+            Err(e) => {
+                println!("Failed to load the last sync token! Starting from scratch.");
+                println!("{:?}", e);
+                None
+            }
+        }
+    }
+
+    fn save_sync_token(&mut self, token: &str) -> () {
+        match self.sync_token_store.save(token) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Failed to save the sync token! Events may be handled twice after a restart.");
+                println!("{:?}", e);
+            }
+        };
+    }
+
+    fn process_sync(&mut self, sync: SyncResponse, since: &str) -> () {
+        for (room_name, room_data) in sync.rooms.join.into_iter() {
+            self.process_joined_room(&room_name, room_data, since);
         }
     }
 
-    fn process_joined_room(&mut self, room_name: &str, room_data: &JoinedRoom) -> () {
+    fn process_joined_room(&mut self, room_name: &RoomId, room_data: JoinedRoom, since: &str) -> () {
         let timeline = match room_data.timeline {
-            Some(ref timeline) => timeline,
+            Some(timeline) => timeline,
             None => { return; }
         };
 
-        for event in timeline.events.iter() {
-            let room_msg = match event {
-                &Event::RoomMessage(ref room_msg) => room_msg,
-                _ => { continue; }
+        let mut events = Vec::new();
+        if timeline.limited {
+            match timeline.prev_batch {
+                Some(ref prev_batch) => {
+                    events = self.fetch_timeline_gap(room_name, prev_batch, since);
+                },
+                None => {
+                    println!("Timeline for room \"{}\" was limited but has no prev_batch; some messages were missed!", room_name);
+                }
             };
+        }
+        events.extend(timeline.events);
 
-            match room_msg.content {
-                RoomMessageOptionType::Message(RoomMessageTypes::TextMessage(ref txt)) => {
-                    let body: &str = txt.body.as_ref();
-                    if body.starts_with(format!("{}: ", self.username).as_str()) == true {
-                        self.process_command(room_name, room_msg.sender.as_ref(), body);
-                    }
-                },
-                _ => ()
+        // Anything deleted before we got to it shouldn't be acted on or end up in a handler's records
+        apply_redactions(&mut events);
+
+        for event in events.iter() {
+            self.process_timeline_event(room_name, event);
+        }
+    }
+
+    // Pages backwards from the start of a limited timeline to where the previous sync left off,
+    // returning the skipped events oldest first
+    fn fetch_timeline_gap(&self, room_name: &RoomId, prev_batch: &str, since: &str) -> Vec<Event> {
+        let mut gap = Vec::new();
+        for event in self.matrix_client.room_messages_iter(room_name, prev_batch, Some(since), Direction::Backward, Some(GAP_PAGE_SIZE), None) {
+            match event {
+                Ok(event) => gap.push(event),
+                Err(e) => {
+                    println!("Failed to fetch missed messages for room \"{}\"!", room_name);
+                    println!("{:?}", e);
+                    break;
+                }
             }
         }
+
+        gap.reverse();
+        gap
+    }
+
+    fn process_timeline_event(&mut self, room_name: &RoomId, event: &Event) -> () {
+        let room_msg = match event {
+            &Event::RoomMessage(ref room_msg) => room_msg,
+            &Event::Reaction(ref reaction) => {
+                for handler in self.reaction_handlers.iter_mut() {
+                    handler(room_name, reaction);
+                }
+                return;
+            },
+            &Event::RoomRedaction(ref redaction) => {
+                for handler in self.redaction_handlers.iter_mut() {
+                    handler(room_name, redaction);
+                }
+                return;
+            },
+            _ => { return; }
+        };
+
+        match room_msg.content {
+            RoomMessageOptionType::Message(RoomMessageTypes::TextMessage(ref txt)) => {
+                let body: &str = txt.body.as_ref();
+                if body.starts_with(format!("{}: ", self.username).as_str()) == true {
+                    self.process_command(room_name, room_msg, body);
+                }
+            },
+            _ => ()
+        }
     }
 
-    fn process_command(&mut self, room_name: &str, sender: Option<&String>, message: &str) -> () {
-        if message == format!("{}: quit", self.username) {
+    fn process_command(&mut self, room_name: &RoomId, command: &EventContainer<RoomMessageOptionType>, message: &str) -> () {
+        let sender = command.sender();
+        let succeeded = if message == format!("{}: quit", self.username) {
             println!("\"{:?}\" in room \"{}\" told us to quit! QUITTIN'!", sender, room_name);
             self.should_quit = true;
+            true
         } else if message.starts_with(format!("{}: say ", self.username).as_str()) {
             let (_, last) = message.split_at(format!("{}: say ", self.username).len());
-            let send_message = if last.len() > 0 {
-                TextMessageType { body: String::from(last) }
+            let markdown = if last.len() > 0 {
+                last
             } else {
-                TextMessageType { body: String::from("I can't say nothing.  That would be weird!") }
+                "I can't say nothing.  That would be weird!"
             };
 
-            match self.matrix_client.send_room_message(room_name, &RoomMessageTypes::TextMessage(send_message)) {
+            match self.respond(room_name, command, TextMessageType::markdown(markdown)) {
                 Ok(event) => {
                     println!("Successfully said a thing! Got: {:?}", event);
+                    true
                 },
                 Err(e) => {
                     println!("Failed to respond!");
                     println!("{:?}", e);
+                    false
                 }
             }
+        } else {
+            // Only say where it was: the message itself is someone's words, not ours to log
+            match command.event_id() {
+                Some(event_id) => println!("Didn't understand event \"{}\" in room \"{}\"", event_id, room_name),
+                None => println!("Didn't understand a message in room \"{}\"", room_name)
+            };
+            false
+        };
+
+        if self.acknowledge_commands {
+            self.acknowledge(room_name, command, succeeded);
+        }
+    }
+
+    fn acknowledge(&self, room_name: &RoomId, command: &EventContainer<RoomMessageOptionType>, succeeded: bool) -> () {
+        let event_id = match command.event_id() {
+            Some(event_id) => event_id,
+            None => { return; }
+        };
+
+        let key = if succeeded { COMMAND_DONE } else { COMMAND_FAILED };
+        match self.matrix_client.react(room_name, event_id, key) {
+            Ok(_) => (),
+            Err(e) => {
+                println!("Failed to acknowledge command!");
+                println!("{:?}", e);
+            }
+        };
+    }
+
+    fn respond(&self, room_name: &RoomId, command: &EventContainer<RoomMessageOptionType>, response: TextMessageType) -> Result<SendEventResponse, MatrixClientError> {
+        let response = RoomMessageTypes::TextMessage(response);
+
+        match (self.reply_style, command.event_id()) {
+            (ReplyStyle::Reply, Some(_)) => self.matrix_client.send_reply(room_name, command, response),
+            (ReplyStyle::Thread, Some(event_id)) => {
+                let thread_root = command.content.relates_to().and_then(RelatesTo::thread_root).unwrap_or(event_id);
+                self.matrix_client.send_thread_message(room_name, thread_root, event_id, response)
+            },
+            _ => self.matrix_client.send_room_message(room_name, &response)
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fake_homeserver::*;
+    use serde_json::json;
+
+    const ROOM: &'static str = "!room:fake.example.org";
+
+    fn bot_for(homeserver: &::std::sync::Arc<FakeHomeserver>) -> MatrixBot {
+        MatrixBot::with_client(homeserver.client(), "jacobian", "hunter2")
+    }
+
+    fn said(homeserver: &FakeHomeserver) -> Vec<String> {
+        homeserver.sent_events().iter()
+                  .map(|e| String::from(e.content["body"].as_str().unwrap_or("")))
+                  .collect()
+    }
+
+    #[test]
+    fn say_command_replies_in_the_same_room() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say hello there")]);
+
+        bot_for(&homeserver).run();
+
+        let sent = homeserver.sent_events();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].room_id, ROOM);
+        assert_eq!(sent[0].event_type, "m.room.message");
+        assert_eq!(sent[0].content["msgtype"], "m.text");
+        assert_eq!(said(&homeserver), vec![String::from("hello there")]);
+    }
+
+    #[test]
+    fn say_renders_markdown() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say run `cargo test` and see [the docs](https://example.org)")]);
+
+        bot_for(&homeserver).run();
+
+        let sent = homeserver.sent_events();
+        assert_eq!(sent[0].content["format"], "org.matrix.custom.html");
+        assert_eq!(sent[0].content["formatted_body"], "<p>run <code>cargo test</code> and see <a href=\"https://example.org\">the docs</a></p>");
+        assert_eq!(said(&homeserver), vec![String::from("run cargo test and see the docs (https://example.org)")]);
+    }
+
+    #[test]
+    fn say_can_answer_with_a_rich_reply() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say hi")]);
+
+        let mut bot = bot_for(&homeserver);
+        bot.set_reply_style(ReplyStyle::Reply);
+        bot.run();
+
+        let sent = homeserver.sent_events();
+        assert_eq!(sent[0].content["m.relates_to"], json!({ "m.in_reply_to": { "event_id": "$incoming0:fake.example.org" } }));
+        assert_eq!(said(&homeserver), vec![String::from("> <@alice:fake.example.org> jacobian: say hi\n\nhi")]);
+    }
+
+    #[test]
+    fn say_can_answer_in_a_new_thread() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say hi")]);
+
+        let mut bot = bot_for(&homeserver);
+        bot.set_reply_style(ReplyStyle::Thread);
+        bot.run();
+
+        assert_eq!(homeserver.sent_events()[0].content["m.relates_to"], json!({
+            "rel_type": "m.thread",
+            "event_id": "$incoming0:fake.example.org",
+            "m.in_reply_to": { "event_id": "$incoming0:fake.example.org" },
+            "is_falling_back": true
+        }));
+        assert_eq!(said(&homeserver), vec![String::from("hi")]);
+    }
+
+    #[test]
+    fn say_stays_in_the_thread_it_was_asked_in() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_events(ROOM, vec![json!({
+            "type": "m.room.message",
+            "event_id": "$command:fake.example.org",
+            "sender": "@alice:fake.example.org",
+            "content": {
+                "msgtype": "m.text",
+                "body": "jacobian: say hi",
+                "m.relates_to": {
+                    "rel_type": "m.thread",
+                    "event_id": "$root:fake.example.org",
+                    "m.in_reply_to": { "event_id": "$earlier:fake.example.org" },
+                    "is_falling_back": true
+                }
+            }
+        })]);
+
+        let mut bot = bot_for(&homeserver);
+        bot.set_reply_style(ReplyStyle::Thread);
+        bot.run();
+
+        let relates_to = &homeserver.sent_events()[0].content["m.relates_to"];
+        assert_eq!(relates_to["event_id"], "$root:fake.example.org");
+        assert_eq!(relates_to["m.in_reply_to"]["event_id"], "$command:fake.example.org");
+    }
+
+    fn reactions(homeserver: &FakeHomeserver) -> Vec<(String, String)> {
+        homeserver.sent_events().iter()
+                  .filter(|e| e.event_type == "m.reaction")
+                  .map(|e| (String::from(e.content["m.relates_to"]["event_id"].as_str().unwrap_or("")),
+                            String::from(e.content["m.relates_to"]["key"].as_str().unwrap_or(""))))
+                  .collect()
+    }
+
+    #[test]
+    fn commands_can_be_acknowledged_with_reactions() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_messages(ROOM, &[
+            ("@alice:fake.example.org", "jacobian: say hi"),
+            ("@alice:fake.example.org", "jacobian: dance"),
+            ("@alice:fake.example.org", "jacobian: quit")
+        ]);
+
+        let mut bot = bot_for(&homeserver);
+        bot.set_acknowledge_commands(true);
+        bot.run();
+
+        assert_eq!(reactions(&homeserver), vec![
+            (String::from("$incoming0:fake.example.org"), String::from("\u{2705}")),
+            (String::from("$incoming1:fake.example.org"), String::from("\u{274c}")),
+            (String::from("$incoming2:fake.example.org"), String::from("\u{2705}"))
+        ]);
+    }
+
+    #[test]
+    fn failed_commands_get_a_cross() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say hi")]);
+        homeserver.respond_with(reqwest::Method::PUT, "rooms", FakeResponse::error(403, "M_FORBIDDEN", "You can't talk here"));
+
+        let mut bot = bot_for(&homeserver);
+        bot.set_acknowledge_commands(true);
+        bot.run();
+
+        assert_eq!(reactions(&homeserver), vec![(String::from("$incoming0:fake.example.org"), String::from("\u{274c}"))]);
+    }
+
+    #[test]
+    fn acknowledgements_which_fail_to_send_are_skipped() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_messages(ROOM, &[
+            ("@alice:fake.example.org", "jacobian: say hi"),
+            ("@alice:fake.example.org", "jacobian: say bye")
+        ]);
+        homeserver.respond_with(reqwest::Method::PUT, "rooms/!room:fake.example.org/send/m.reaction",
+                                FakeResponse::error(403, "M_FORBIDDEN", "No reactions here"));
+
+        let mut bot = bot_for(&homeserver);
+        bot.set_acknowledge_commands(true);
+        bot.run();
+
+        let messages = homeserver.sent_events().into_iter().filter(|e| e.event_type == "m.room.message").count();
+        assert_eq!(messages, 2);
+        assert_eq!(reactions(&homeserver), vec![(String::from("$incoming1:fake.example.org"), String::from("\u{2705}"))]);
+    }
+
+    #[test]
+    fn reactions_are_passed_to_handlers() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_events(ROOM, vec![json!({
+            "type": "m.reaction",
+            "event_id": "$reaction:fake.example.org",
+            "sender": "@alice:fake.example.org",
+            "content": { "m.relates_to": { "rel_type": "m.annotation", "event_id": "$said:fake.example.org", "key": "\u{1f44d}" } }
+        })]);
+
+        let seen = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        let mut bot = bot_for(&homeserver);
+        let handler_seen = seen.clone();
+        bot.add_reaction_handler(move |room, reaction| {
+            handler_seen.borrow_mut().push((room.to_string(), reaction.content.reacts_to().map(EventId::to_string), reaction.content.key().map(String::from)));
+        });
+        bot.run();
+
+        assert_eq!(*seen.borrow(), vec![(String::from(ROOM), Some(String::from("$said:fake.example.org")), Some(String::from("\u{1f44d}")))]);
+    }
+
+    #[test]
+    fn commands_redacted_in_the_same_sync_are_not_run() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_events(ROOM, vec![
+            json!({ "type": "m.room.message", "event_id": "$say:fake.example.org", "sender": "@alice:fake.example.org",
+                    "content": { "msgtype": "m.text", "body": "jacobian: say something regrettable" } }),
+            json!({ "type": "m.room.redaction", "event_id": "$r:fake.example.org", "sender": "@alice:fake.example.org",
+                    "redacts": "$say:fake.example.org", "content": {} })
+        ]);
+
+        bot_for(&homeserver).run();
+
+        assert!(homeserver.sent_events().is_empty());
+    }
+
+    #[test]
+    fn redactions_are_passed_to_handlers() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_events(ROOM, vec![json!({
+            "type": "m.room.redaction", "event_id": "$r:fake.example.org", "sender": "@alice:fake.example.org",
+            "redacts": "$long_ago:fake.example.org", "content": { "reason": "oops" }
+        })]);
+
+        let seen = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        let mut bot = bot_for(&homeserver);
+        let handler_seen = seen.clone();
+        bot.add_redaction_handler(move |room, redaction| {
+            handler_seen.borrow_mut().push((room.to_string(), redaction.redacts().map(EventId::to_string)));
+        });
+        bot.run();
+
+        assert_eq!(*seen.borrow(), vec![(String::from(ROOM), Some(String::from("$long_ago:fake.example.org")))]);
+    }
+
+    fn sync_sinces(homeserver: &FakeHomeserver) -> Vec<Option<String>> {
+        homeserver.requests_to("sync").iter()
+                  .map(|r| r.query.get("since").cloned())
+                  .collect()
+    }
+
+    #[test]
+    fn commands_in_a_limited_timelines_gap_are_handled_first() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_limited_events(ROOM, "p_gap", vec![
+            FakeHomeserver::text_message("$after:fake.example.org", "@alice:fake.example.org", "jacobian: say after the gap")
+        ]);
+        homeserver.push_messages_page(ROOM, vec![
+            FakeHomeserver::text_message("$second:fake.example.org", "@alice:fake.example.org", "jacobian: say second in the gap")
+        ], "p_older");
+        homeserver.push_messages_page(ROOM, vec![
+            FakeHomeserver::text_message("$first:fake.example.org", "@alice:fake.example.org", "jacobian: say first in the gap")
+        ], "p_oldest");
+
+        bot_for(&homeserver).run();
+
+        assert_eq!(said(&homeserver), vec![
+            String::from("first in the gap"),
+            String::from("second in the gap"),
+            String::from("after the gap")
+        ]);
+
+        let pages = homeserver.requests_to(format!("rooms/{}/messages", ROOM).as_str());
+        assert_eq!(pages[0].query.get("from"), Some(&String::from("p_gap")));
+        assert_eq!(pages[0].query.get("to"), Some(&String::from("s1")));
+        assert_eq!(pages[0].query.get("dir"), Some(&String::from("b")));
+        assert_eq!(pages[1].query.get("from"), Some(&String::from("p_older")));
+    }
+
+    #[test]
+    fn catching_up_resumes_from_the_saved_token() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say missed you")]);
+        let store = MemorySyncTokenStore::new(Some("s_saved"));
+
+        let mut bot = bot_for(&homeserver);
+        bot.set_sync_token_store(Box::new(store.clone()));
+        bot.run();
+
+        assert_eq!(sync_sinces(&homeserver), vec![Some(String::from("s_saved")), Some(String::from("s1"))]);
+        assert_eq!(said(&homeserver), vec![String::from("missed you")]);
+        assert_eq!(store.load().unwrap(), Some(String::from("s1")));
+    }
+
+    #[test]
+    fn skipping_the_backlog_ignores_the_saved_token() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say old news")]);
+        let store = MemorySyncTokenStore::new(Some("s_saved"));
+
+        let mut bot = bot_for(&homeserver);
+        bot.set_sync_token_store(Box::new(store.clone()));
+        bot.set_backlog(Backlog::Skip);
+        bot.run();
+
+        assert_eq!(sync_sinces(&homeserver)[0], None);
+        assert!(homeserver.sent_events().is_empty());
+        assert_eq!(store.load().unwrap(), Some(String::from("s1")));
+    }
+
+    #[test]
+    fn a_refused_saved_token_falls_back_to_an_initial_sync() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.respond_with(reqwest::Method::GET, "sync", FakeResponse::error(400, "M_UNKNOWN", "Invalid stream token"));
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say from before")]);
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say from now on")]);
+        let store = MemorySyncTokenStore::new(Some("s_stale"));
+
+        let mut bot = bot_for(&homeserver);
+        bot.set_sync_token_store(Box::new(store.clone()));
+        bot.run();
+
+        assert_eq!(sync_sinces(&homeserver), vec![
+            Some(String::from("s_stale")),
+            None,
+            Some(String::from("s1")),
+            Some(String::from("s2"))
+        ]);
+        assert_eq!(said(&homeserver), vec![String::from("from before"), String::from("from now on")]);
+        assert_eq!(store.load().unwrap(), Some(String::from("s2")));
+    }
+
+    #[test]
+    fn a_refused_saved_token_backfills_limited_timelines_in_the_initial_sync() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.respond_with(reqwest::Method::GET, "sync", FakeResponse::error(400, "M_INVALID_PARAM", "Invalid stream token"));
+        homeserver.push_limited_events(ROOM, "p_gap", vec![
+            FakeHomeserver::text_message("$latest:fake.example.org", "@alice:fake.example.org", "jacobian: say latest")
+        ]);
+        homeserver.push_messages_page(ROOM, vec![
+            FakeHomeserver::text_message("$missed:fake.example.org", "@alice:fake.example.org", "jacobian: say missed")
+        ], "s_stale");
+        let store = MemorySyncTokenStore::new(Some("s_stale"));
+
+        let mut bot = bot_for(&homeserver);
+        bot.set_sync_token_store(Box::new(store.clone()));
+        bot.run();
+
+        let messages = homeserver.requests_to("rooms/!room:fake.example.org/messages");
+        assert_eq!(messages[0].query.get("from"), Some(&String::from("p_gap")));
+        assert_eq!(messages[0].query.get("to"), Some(&String::from("s_stale")));
+        assert_eq!(said(&homeserver), vec![String::from("missed"), String::from("latest")]);
+    }
+
+    #[test]
+    fn a_saved_token_is_kept_when_the_homeserver_is_unavailable() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.respond_with(reqwest::Method::GET, "sync", FakeResponse::error(503, "M_UNKNOWN", "Service unavailable"));
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say hello")]);
+        let store = MemorySyncTokenStore::new(Some("s_saved"));
+
+        let mut client = homeserver.client();
+        client.set_retry_policy(RetryPolicy::never());
+        let mut bot = MatrixBot::with_client(client, "jacobian", "hunter2");
+        bot.set_sync_token_store(Box::new(store.clone()));
+        bot.run();
+
+        assert_eq!(sync_sinces(&homeserver), vec![Some(String::from("s_saved"))]);
+        assert!(homeserver.sent_events().is_empty());
+        assert_eq!(store.load().unwrap(), Some(String::from("s_saved")));
+    }
+
+    #[test]
+    fn the_token_is_saved_once_a_quit_is_handled() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: quit")]);
+        let store = MemorySyncTokenStore::new(None);
+
+        let mut bot = bot_for(&homeserver);
+        bot.set_sync_token_store(Box::new(store.clone()));
+        bot.run();
+
+        // Restarting must not see the quit again
+        assert_eq!(store.load().unwrap(), Some(String::from("s2")));
+    }
+
+    #[test]
+    fn say_with_nothing_to_say_complains() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say ")]);
+
+        bot_for(&homeserver).run();
+
+        assert_eq!(said(&homeserver), vec![String::from("I can't say nothing.  That would be weird!")]);
+    }
+
+    #[test]
+    fn messages_not_addressed_to_the_bot_are_ignored() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_messages(ROOM, &[
+            ("@alice:fake.example.org", "say hello"),
+            ("@alice:fake.example.org", "someone_else: say hello")
+        ]);
+
+        bot_for(&homeserver).run();
+
+        assert!(homeserver.sent_events().is_empty());
+    }
+
+    #[test]
+    fn initial_sync_is_not_acted_on() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say old news")]);
+
+        bot_for(&homeserver).run();
+
+        assert!(homeserver.sent_events().is_empty());
+    }
+
+    #[test]
+    fn quit_stops_syncing_and_logs_out() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: quit")]);
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say too late")]);
+
+        bot_for(&homeserver).run();
+
+        assert_eq!(homeserver.requests_to("sync").len(), 2);
+        assert!(homeserver.sent_events().is_empty());
+        assert!(!homeserver.is_logged_in());
+    }
+
+    #[test]
+    fn each_sync_continues_from_the_last_batch() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_empty_sync();
+
+        bot_for(&homeserver).run();
+
+        let since: Vec<Option<String>> = homeserver.requests_to("sync").iter()
+                                                   .map(|r| r.query.get("since").cloned())
+                                                   .collect();
+        assert_eq!(since, vec![None, Some(String::from("s1")), Some(String::from("s2"))]);
+    }
+
+    #[test]
+    fn sync_timeouts_are_retried() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.respond_with(reqwest::Method::GET, "sync", FakeResponse::Timeout);
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "jacobian: say still here")]);
+
+        let mut client = homeserver.client();
+        client.set_retry_policy(quick_retries());
+        MatrixBot::with_client(client, "jacobian", "hunter2").run();
+
+        assert_eq!(said(&homeserver), vec![String::from("still here")]);
+    }
+
+    #[test]
+    fn the_bot_gives_up_once_syncs_keep_timing_out() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        for _ in 0..3 {
+            homeserver.respond_with(reqwest::Method::GET, "sync", FakeResponse::Timeout);
+        }
+        homeserver.push_empty_sync();
+
+        let mut client = homeserver.client();
+        client.set_retry_policy(RetryPolicy {
+            max_retries: 2,
+            .. quick_retries()
+        });
+        MatrixBot::with_client(client, "jacobian", "hunter2").run();
+
+        // The first attempt and two retries
+        assert_eq!(homeserver.requests_to("sync").len(), 3);
+        assert!(!homeserver.is_logged_in());
+    }
+
+    #[test]
+    fn bad_password_never_syncs() {
+        let homeserver = FakeHomeserver::new("jacobian", "correct horse");
+        homeserver.push_empty_sync();
+
+        bot_for(&homeserver).run();
+
+        assert!(homeserver.requests_to("sync").is_empty());
+    }
+
+    #[test]
+    fn unknown_events_do_not_stop_the_bot() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        homeserver.push_empty_sync();
+        homeserver.push_sync(json!({
+            "rooms": {
+                "join": {
+                    ROOM: {
+                        "timeline": {
+                            "limited": false,
+                            "events": [
+                                { "type": "m.room.encrypted", "sender": "@alice:fake.example.org", "content": { "ciphertext": "abc" } },
+                                { "type": "m.room.message", "sender": "@alice:fake.example.org", "content": { "msgtype": "m.text", "body": "jacobian: say still parsing" } }
+                            ]
+                        }
+                    }
+                },
+                "invite": {},
+                "leave": {}
+            },
+            "presence": { "events": [] }
+        }));
+
+        bot_for(&homeserver).run();
+
+        assert_eq!(said(&homeserver), vec![String::from("still parsing")]);
+    }
+}
@@ -3,11 +3,25 @@ extern crate reqwest;
 extern crate chrono;
 
 use std::collections::HashMap;
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Instant;
 use std::io::{Read};
+use std::fs::File;
+use std::path::Path;
 
 use chrono::prelude::*;
 
+pub use matrix_error::{ErrorCode, MatrixError};
+pub use matrix_identifiers::*;
+use matrix_core::*;
+pub use matrix_core::{RetryPolicy, Timeouts};
+pub use matrix_transport::{HttpTransport, ReqwestTransport};
+pub use matrix_html::{HTML_FORMAT, decode_entities, escape_html, html_to_plaintext, markdown_to_html, sanitize_html};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::DeserializeOwned;
+
 #[derive(Deserialize, Debug, Default)]
 pub struct VersionResponse {
     pub versions: Vec<String>
@@ -33,24 +47,27 @@ pub struct LoginRequest {
     pub device_id: Option<String>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug)]
 pub struct LoginResponse {
     pub access_token: String,
     pub home_server: String,
-    pub user_id: String,
+    pub user_id: UserId,
     pub refresh_token: Option<String>,
     pub device_id: Option<String>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug)]
 pub struct PublicRoomsChunk {
     pub world_readable: bool,
     pub topic: Option<String>,
     pub num_joined_members: i64,
     pub avatar_url: String,
-    pub room_id: String,
+    pub room_id: RoomId,
     pub guest_can_join: bool,
-    pub aliases: Option<Vec<String>>,
+
+    #[serde(default, deserialize_with = "deserialize_valid_ids")]
+    pub aliases: Vec<RoomAliasId>,
+
     pub name: String
 }
 
@@ -61,30 +78,141 @@ pub struct PublicRoomsResponse {
     pub end: Option<String>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug)]
 pub struct JoinResponse {
-    pub room_id: String
+    pub room_id: RoomId
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RoomVisibility {
+    #[serde(rename = "public")]
+    Public,
+
+    #[serde(rename = "private")]
+    Private
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RoomPreset {
+    #[serde(rename = "private_chat")]
+    PrivateChat,
+
+    #[serde(rename = "public_chat")]
+    PublicChat,
+
+    #[serde(rename = "trusted_private_chat")]
+    TrustedPrivateChat
+}
+
+#[derive(Serialize, Debug)]
+pub struct InitialStateEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+
+    pub state_key: String,
+
+    pub content: serde_json::Value
+}
+
+impl InitialStateEvent {
+    pub fn new<T: Serialize>(event_type: &str, state_key: &str, content: &T) -> Result<InitialStateEvent, MatrixClientError> {
+        Ok(InitialStateEvent {
+            event_type: String::from(event_type),
+            state_key: String::from(state_key),
+            content: serde_json::to_value(content).map_err(MatrixClientError::Json)?
+        })
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct CreateRoomRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<RoomVisibility>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<RoomPreset>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_alias_name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invite: Option<Vec<UserId>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_direct: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_state: Option<Vec<InitialStateEvent>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power_level_content_override: Option<RoomPowerLevelsEvent>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_version: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateRoomResponse {
+    pub room_id: RoomId
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct EmptyRequest {
+}
+
+#[derive(Serialize, Debug)]
+pub struct RoomMembershipRequest {
+    pub user_id: UserId,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct UnreadNotificationCounts {
     pub highlight_count: Option<i64>,
     pub notification_count: Option<i64>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct Unsigned<T> {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub prev_content: Option<T>,
+
+    #[serde(default)]
     pub age: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub transaction_id: Option<String>,
-    pub redacted_by: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redacted_by: Option<EventId>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub redacted_because: Option<Box<EventContainer<RoomRedactionEvent>>>
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(tag = "type")]
+// Anything which doesn't parse as one of the events above: either a type we don't know about
+// (m.room.encrypted, custom types, ...) or a known type with content we can't make sense of
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UnknownEvent {
+    pub event_type: String,
+    pub json: serde_json::Value
+}
+
+// (De)serialized by hand below, falling back to Event::Unknown, so the derived impls are only
+// used for the known event types
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", remote = "Event")]
 pub enum Event {
-    Unknown,
+    #[serde(skip)]
+    Unknown(UnknownEvent),
 
     #[serde(rename = "m.typing")]
     Typing(EventContainer<TypingEvent>),
@@ -99,7 +227,7 @@ pub enum Event {
     RoomTopic(EventContainer<RoomTopicEvent>),
 
     #[serde(rename = "m.receipt")]
-    Receipt(EventContainer<HashMap<String, ReceiptEvent>>),
+    Receipt(EventContainer<HashMap<EventId, ReceiptEvent>>),
 
     #[serde(rename = "m.presence")]
     Presence(EventContainer<PresenceEvent>),
@@ -133,19 +261,47 @@ pub enum Event {
 
     #[serde(rename = "m.room.redaction")]
     RoomRedaction(EventContainer<RoomRedactionEvent>),
+
+    #[serde(rename = "m.reaction")]
+    Reaction(EventContainer<ReactionEvent>),
 }
 
-#[derive(Deserialize, Debug)]
+// An annotation on another event, usually an emoji. Redacted reactions have no relation left.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReactionEvent {
+    #[serde(rename = "m.relates_to", skip_serializing_if = "Option::is_none")]
+    pub relates_to: Option<RelatesTo>
+}
+
+impl ReactionEvent {
+    pub fn new(event_id: &EventId, key: &str) -> ReactionEvent {
+        ReactionEvent {
+            relates_to: Some(RelatesTo::annotation(event_id, key))
+        }
+    }
+
+    // The event being reacted to
+    pub fn reacts_to(&self) -> Option<&EventId> {
+        self.relates_to.as_ref().and_then(RelatesTo::annotates)
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        self.relates_to.as_ref().and_then(|relates_to| relates_to.key.as_ref()).map(String::as_str)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct RoomRedactionEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub reason: Option<String>
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct GuestAccessEvent {
     pub guest_access: GuestAccess
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum GuestAccess {
     Unknown,
 
@@ -158,42 +314,236 @@ pub enum GuestAccess {
 
 impl Default for Event {
     fn default() -> Event {
-        Event::Unknown
+        Event::Unknown(UnknownEvent::default())
     }
 }
 
-#[derive(Deserialize, Debug, Default)]
+// Keys which survive redaction: those at the top level of any event, then those in the content
+// of each kind of event which needs them to keep the room's state making sense
+const REDACTION_KEPT_KEYS: [&'static str; 15] = [
+    "event_id", "type", "room_id", "sender", "state_key", "content", "hashes", "signatures", "depth",
+    "prev_events", "prev_state", "auth_events", "origin", "origin_server_ts", "membership"
+];
+
+fn keep_keys(map: serde_json::Map<String, serde_json::Value>, keys: &[&str]) -> serde_json::Map<String, serde_json::Value> {
+    map.into_iter().filter(|&(ref key, _)| keys.contains(&key.as_str())).collect()
+}
+
+fn redaction_kept_content_keys(event_type: &str) -> &'static [&'static str] {
+    match event_type {
+        "m.room.member" => &["membership"],
+        "m.room.create" => &["creator"],
+        "m.room.join_rules" => &["join_rule"],
+        "m.room.power_levels" => &["ban", "events", "events_default", "kick", "redact", "state_default", "users", "users_default"],
+        "m.room.aliases" => &["aliases"],
+        "m.room.history_visibility" => &["history_visibility"],
+        _ => &[]
+    }
+}
+
+impl Event {
+    pub fn event_id(&self) -> Option<&str> {
+        let event_id = match *self {
+            Event::Unknown(ref unknown) => { return unknown.json.get("event_id").and_then(|event_id| event_id.as_str()); },
+            Event::Typing(ref event) => event.event_id(),
+            Event::RoomPowerLevels(ref event) => event.event_id(),
+            Event::RoomHistoryVisibility(ref event) => event.event_id(),
+            Event::RoomTopic(ref event) => event.event_id(),
+            Event::Receipt(ref event) => event.event_id(),
+            Event::Presence(ref event) => event.event_id(),
+            Event::RoomMember(ref event) => event.event_id(),
+            Event::RoomAlias(ref event) => event.event_id(),
+            Event::RoomCanonicalAlias(ref event) => event.event_id(),
+            Event::RoomCreate(ref event) => event.event_id(),
+            Event::RoomAvatar(ref event) => event.event_id(),
+            Event::RoomJoinRules(ref event) => event.event_id(),
+            Event::RoomMessage(ref event) => event.event_id(),
+            Event::RoomName(ref event) => event.event_id(),
+            Event::RoomGuestAccess(ref event) => event.event_id(),
+            Event::RoomRedaction(ref event) => event.event_id(),
+            Event::Reaction(ref event) => event.event_id()
+        };
+
+        event_id.map(EventId::as_str)
+    }
+
+    // Strips this event down to what the spec's redaction algorithm keeps, remembering the
+    // redaction which caused it. Known events whose content can't be empty become Event::Unknown,
+    // and the original content is never kept, even if the event can't be written out as JSON.
+    pub fn redact(&mut self, redaction: &EventContainer<RoomRedactionEvent>) {
+        let json = match serde_json::to_value(&*self) {
+            Ok(serde_json::Value::Object(json)) => json,
+            _ => {
+                let mut json = serde_json::Map::new();
+                match self.event_id() {
+                    Some(event_id) => { json.insert(String::from("event_id"), serde_json::json!(event_id)); },
+                    None => ()
+                };
+                json
+            }
+        };
+
+        let event_type = json.get("type").and_then(|t| t.as_str()).map(String::from).unwrap_or_default();
+        let kept_content_keys = redaction_kept_content_keys(event_type.as_str());
+
+        let age = json.get("unsigned").and_then(|unsigned| unsigned.get("age")).cloned();
+        let mut json = keep_keys(json, &REDACTION_KEPT_KEYS);
+
+        let content = match json.remove("content") {
+            Some(serde_json::Value::Object(content)) => keep_keys(content, kept_content_keys),
+            _ => serde_json::Map::new()
+        };
+        json.insert(String::from("content"), serde_json::Value::Object(content));
+
+        let mut unsigned = serde_json::Map::new();
+        match age {
+            Some(age) => { unsigned.insert(String::from("age"), age); },
+            None => ()
+        };
+        match redaction.event_id {
+            Some(ref event_id) => { unsigned.insert(String::from("redacted_by"), serde_json::json!(event_id)); },
+            None => ()
+        };
+        match serde_json::to_value(redaction) {
+            Ok(because) => { unsigned.insert(String::from("redacted_because"), because); },
+            Err(_) => ()
+        };
+        json.insert(String::from("unsigned"), serde_json::Value::Object(unsigned));
+
+        let json = serde_json::Value::Object(json);
+        *self = match serde_json::from_value(json.clone()) {
+            Ok(redacted) => redacted,
+            Err(_) => Event::Unknown(UnknownEvent { event_type: event_type, json: json })
+        };
+    }
+}
+
+// Applies each redaction in `events` to the events before it, so that content which was already
+// deleted by the time we saw it is never acted on. Only events in the same batch are affected: a
+// redaction of something from an earlier sync or /messages page is left for the caller to handle.
+pub fn apply_redactions(events: &mut [Event]) {
+    for i in 0..events.len() {
+        let (earlier, rest) = events.split_at_mut(i);
+
+        let redaction = match rest[0] {
+            Event::RoomRedaction(ref redaction) => redaction,
+            _ => continue
+        };
+
+        let redacts = match redaction.redacts() {
+            Some(redacts) => redacts,
+            None => continue
+        };
+
+        match earlier.iter_mut().find(|event| event.event_id() == Some(redacts.as_str())) {
+            Some(event) => event.redact(redaction),
+            None => ()
+        };
+    }
+}
+
+// Unknown events are written back out exactly as they were received
+impl Serialize for Event {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        match *self {
+            Event::Unknown(ref unknown) => unknown.json.serialize(serializer),
+            _ => Event::serialize(self, serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Event {
+    fn deserialize<D>(deserializer: D) -> Result<Event, D::Error> where D: Deserializer<'de> {
+        let json = serde_json::Value::deserialize(deserializer)?;
+
+        match Event::deserialize(&json) {
+            Ok(event) => Ok(event),
+            Err(_) => {
+                let event_type = json.get("type").and_then(|x| x.as_str()).map(String::from).unwrap_or_default();
+                Ok(Event::Unknown(UnknownEvent { event_type: event_type, json: json }))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct EventContainer<T> {
     pub content: T,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<EventId>,
+
+    // Left out of events which arrive inside a room's section of a sync
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<RoomId>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub origin_server_ts: Option<i64>,
-    pub sender: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender: Option<UserId>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub unsigned: Option<Unsigned<T>>,
-    pub state_key: Option<String>
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_key: Option<String>,
+
+    // Only set on m.room.redaction events: the event being redacted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redacts: Option<EventId>
 }
 
-#[derive(Deserialize, Debug, Default)]
+impl<T> EventContainer<T> {
+    pub fn event_id(&self) -> Option<&EventId> {
+        self.event_id.as_ref()
+    }
+
+    pub fn room_id(&self) -> Option<&RoomId> {
+        self.room_id.as_ref()
+    }
+
+    pub fn sender(&self) -> Option<&UserId> {
+        self.sender.as_ref()
+    }
+
+    pub fn state_key(&self) -> Option<&str> {
+        self.state_key.as_ref().map(String::as_str)
+    }
+
+    pub fn redacts(&self) -> Option<&EventId> {
+        self.redacts.as_ref()
+    }
+
+    // When the event was sent, according to the server it was sent from
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        self.origin_server_ts.and_then(|ts| Utc.timestamp_millis_opt(ts).single())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct Receipt {
     pub ts: i64
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct ReceiptEvent {
     #[serde(rename = "m.read")]
-    pub read: HashMap<String, Receipt>
+    pub read: HashMap<UserId, Receipt>
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct RedactedMessageContent {
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum RoomMessageOptionType {
     Message(RoomMessageTypes),
     Redacted(RedactedMessageContent)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag = "msgtype")]
 pub enum RoomMessageTypes {
     Unknown,
@@ -229,212 +579,684 @@ impl Default for RoomMessageTypes {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+// Evaluates $body with $message bound to the content of whichever kind of message $value is
+macro_rules! on_message_type {
+    ($value:expr, $message:ident => $body:expr, Unknown => $unknown:expr) => {
+        match $value {
+            RoomMessageTypes::Unknown => $unknown,
+            RoomMessageTypes::AudioMessage($message) => $body,
+            RoomMessageTypes::VideoMessage($message) => $body,
+            RoomMessageTypes::LocationMessage($message) => $body,
+            RoomMessageTypes::FileMessage($message) => $body,
+            RoomMessageTypes::ImageMessage($message) => $body,
+            RoomMessageTypes::NoticeMessage($message) => $body,
+            RoomMessageTypes::EmoteMessage($message) => $body,
+            RoomMessageTypes::TextMessage($message) => $body
+        }
+    }
+}
+
+impl RoomMessageTypes {
+    pub fn body(&self) -> Option<&str> {
+        on_message_type!(self, message => Some(message.body.as_str()), Unknown => None)
+    }
+
+    pub fn relates_to(&self) -> Option<&RelatesTo> {
+        on_message_type!(self, message => message.relates_to.as_ref(), Unknown => None)
+    }
+
+    // Does nothing for unknown messages, which have nowhere to keep it
+    pub fn set_relates_to(&mut self, relates_to: Option<RelatesTo>) -> () {
+        on_message_type!(self, message => { message.relates_to = relates_to; }, Unknown => ())
+    }
+
+    // Makes this a rich reply to `original`, which was sent in `room`. Text, emote and notice
+    // messages also get a quote of the original for clients which don't show replies.
+    pub fn into_reply(mut self, room: &RoomId, original: &EventContainer<RoomMessageOptionType>) -> Result<RoomMessageTypes, MatrixClientError> {
+        let event_id = original.event_id().ok_or(MatrixClientError::MissingEventId)?;
+        let (quote, html_quote) = reply_fallback(room, event_id, original);
+
+        match self {
+            RoomMessageTypes::TextMessage(ref mut message) => message.add_reply_fallback(quote.as_str(), html_quote.as_str()),
+            RoomMessageTypes::EmoteMessage(ref mut message) => message.add_reply_fallback(quote.as_str(), html_quote.as_str()),
+            RoomMessageTypes::NoticeMessage(ref mut message) => message.add_reply_fallback(quote.as_str(), html_quote.as_str()),
+            _ => ()
+        };
+
+        self.set_relates_to(Some(RelatesTo::reply(event_id)));
+        Ok(self)
+    }
+
+    // The replacement content, if this is an edit
+    pub fn new_content(&self) -> Option<&RoomMessageTypes> {
+        on_message_type!(self, message => message.new_content.as_ref().map(|new_content| &**new_content), Unknown => None)
+    }
+
+    // The event this edits, if it's an edit
+    pub fn replaces(&self) -> Option<&EventId> {
+        self.relates_to().and_then(RelatesTo::replaces)
+    }
+
+    // Makes this an edit replacing the content of `original`. Clients which don't understand edits
+    // see it as a new message with "* " in front.
+    pub fn into_edit(self, original: &EventId) -> RoomMessageTypes {
+        let mut new_content = self;
+        new_content.set_relates_to(None);
+
+        let mut edit = new_content.clone();
+        match edit {
+            RoomMessageTypes::TextMessage(ref mut message) => message.prefix_bodies("* "),
+            RoomMessageTypes::EmoteMessage(ref mut message) => message.prefix_bodies("* "),
+            RoomMessageTypes::NoticeMessage(ref mut message) => message.prefix_bodies("* "),
+            ref mut other => on_message_type!(other, message => { message.body = format!("* {}", message.body); }, Unknown => ())
+        };
+
+        on_message_type!(&mut edit, message => {
+            message.new_content = Some(Box::new(new_content));
+            message.relates_to = Some(RelatesTo::replace(original));
+        }, Unknown => ());
+
+        edit
+    }
+
+    // Puts this into the thread starting at `thread_root`. Clients without threads show it as a
+    // reply to `latest_event`, which should be the newest event in the thread.
+    pub fn into_thread_message(mut self, thread_root: &EventId, latest_event: &EventId) -> RoomMessageTypes {
+        self.set_relates_to(Some(RelatesTo::thread(thread_root, latest_event)));
+        self
+    }
+}
+
+impl RoomMessageOptionType {
+    pub fn relates_to(&self) -> Option<&RelatesTo> {
+        match *self {
+            RoomMessageOptionType::Message(ref message) => message.relates_to(),
+            RoomMessageOptionType::Redacted(_) => None
+        }
+    }
+
+    pub fn replaces(&self) -> Option<&EventId> {
+        self.relates_to().and_then(RelatesTo::replaces)
+    }
+}
+
+// The quote of `original` a reply starts with, as plain text and as HTML
+fn reply_fallback(room: &RoomId, event_id: &EventId, original: &EventContainer<RoomMessageOptionType>) -> (String, String) {
+    let sender = original.sender().map(UserId::as_str).unwrap_or("");
+
+    let (body, html, emote) = match original.content {
+        RoomMessageOptionType::Message(RoomMessageTypes::TextMessage(ref message)) => (message.body_without_reply_fallback(), message.sanitized_html_body(), ""),
+        RoomMessageOptionType::Message(RoomMessageTypes::NoticeMessage(ref message)) => (message.body_without_reply_fallback(), message.sanitized_html_body(), ""),
+        RoomMessageOptionType::Message(RoomMessageTypes::EmoteMessage(ref message)) => (message.body_without_reply_fallback(), message.sanitized_html_body(), "* "),
+        RoomMessageOptionType::Message(ref message) => (message.body().unwrap_or(""), None, ""),
+        RoomMessageOptionType::Redacted(_) => ("", None, "")
+    };
+
+    let mut lines = body.lines();
+    let mut quote = format!("> {}<{}> {}", emote, sender, lines.next().unwrap_or(""));
+    for line in lines {
+        quote.push_str(format!("\n> {}", line).as_str());
+    }
+
+    let html = html.unwrap_or_else(|| escape_html(body).replace('\n', "<br>"));
+    let html_quote = format!("<blockquote><a href=\"https://matrix.to/#/{}/{}\">In reply to</a> {}<a href=\"https://matrix.to/#/{}\">{}</a><br>{}</blockquote>",
+                             room, event_id, emote, escape_html(sender), escape_html(sender), html);
+
+    (quote.trim_end().to_string(), html_quote)
+}
+
+// Drops the "> " quote a reply's body starts with, along with the blank line after it
+fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> <") {
+        return body;
+    }
+
+    let mut rest = body;
+    while rest.starts_with('>') {
+        rest = match rest.find('\n') {
+            Some(newline) => &rest[newline + 1..],
+            None => ""
+        };
+    }
+
+    if rest.starts_with('\n') {
+        &rest[1..]
+    } else {
+        rest
+    }
+}
+
+pub const THREAD_REL_TYPE: &'static str = "m.thread";
+pub const REPLACE_REL_TYPE: &'static str = "m.replace";
+pub const ANNOTATION_REL_TYPE: &'static str = "m.annotation";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InReplyTo {
+    pub event_id: EventId
+}
+
+// m.relates_to, saying how a message relates to an earlier event. A rich reply only has
+// in_reply_to. A message in a thread has rel_type m.thread and the thread root as event_id, and
+// its in_reply_to is only there for clients without threads unless is_falling_back is false. An
+// edit has rel_type m.replace and the edited event as event_id, and a reaction has rel_type
+// m.annotation with the event reacted to and the reaction itself as key.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct RelatesTo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rel_type: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<EventId>,
+
+    #[serde(rename = "m.in_reply_to", skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<InReplyTo>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_falling_back: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>
+}
+
+impl RelatesTo {
+    pub fn reply(event_id: &EventId) -> RelatesTo {
+        RelatesTo {
+            in_reply_to: Some(InReplyTo { event_id: event_id.clone() }),
+            .. RelatesTo::default()
+        }
+    }
+
+    pub fn thread(thread_root: &EventId, latest_event: &EventId) -> RelatesTo {
+        RelatesTo {
+            rel_type: Some(String::from(THREAD_REL_TYPE)),
+            event_id: Some(thread_root.clone()),
+            in_reply_to: Some(InReplyTo { event_id: latest_event.clone() }),
+            is_falling_back: Some(true),
+            key: None
+        }
+    }
+
+    // A reply to `event_id` which is itself in the thread starting at `thread_root`
+    pub fn thread_reply(thread_root: &EventId, event_id: &EventId) -> RelatesTo {
+        RelatesTo {
+            is_falling_back: Some(false),
+            .. RelatesTo::thread(thread_root, event_id)
+        }
+    }
+
+    // The event this really replies to, as opposed to a thread's fallback
+    pub fn reply_to(&self) -> Option<&EventId> {
+        match self.is_falling_back {
+            Some(true) => None,
+            _ => self.in_reply_to.as_ref().map(|in_reply_to| &in_reply_to.event_id)
+        }
+    }
+
+    pub fn replace(event_id: &EventId) -> RelatesTo {
+        RelatesTo {
+            rel_type: Some(String::from(REPLACE_REL_TYPE)),
+            event_id: Some(event_id.clone()),
+            .. RelatesTo::default()
+        }
+    }
+
+    pub fn annotation(event_id: &EventId, key: &str) -> RelatesTo {
+        RelatesTo {
+            rel_type: Some(String::from(ANNOTATION_REL_TYPE)),
+            event_id: Some(event_id.clone()),
+            key: Some(String::from(key)),
+            .. RelatesTo::default()
+        }
+    }
+
+    pub fn thread_root(&self) -> Option<&EventId> {
+        self.related_by(THREAD_REL_TYPE)
+    }
+
+    // The event this edits
+    pub fn replaces(&self) -> Option<&EventId> {
+        self.related_by(REPLACE_REL_TYPE)
+    }
+
+    // The event this reacts to
+    pub fn annotates(&self) -> Option<&EventId> {
+        self.related_by(ANNOTATION_REL_TYPE)
+    }
+
+    fn related_by(&self, wanted: &str) -> Option<&EventId> {
+        match self.rel_type {
+            Some(ref rel_type) if rel_type == wanted => self.event_id.as_ref(),
+            _ => None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct AudioInfo {
     pub mimetype: Option<String>,
     pub duration: Option<i64>,
     pub size: Option<i64>
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct AudioMessageType {
     pub body: String,
     pub info: Option<AudioInfo>,
-    pub url: String
+    pub url: MxcUri,
+
+    #[serde(rename = "m.new_content", skip_serializing_if = "Option::is_none")]
+    pub new_content: Option<Box<RoomMessageTypes>>,
+
+    #[serde(rename = "m.relates_to", skip_serializing_if = "Option::is_none")]
+    pub relates_to: Option<RelatesTo>
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct VideoInfo {
     pub mimetype: Option<String>,
     pub thumbnail_info: Option<ImageInfo>,
     pub h: Option<i64>,
-    pub thumbnail_url: Option<String>,
+    pub thumbnail_url: Option<MxcUri>,
     pub w: Option<i64>,
     pub duration: Option<i64>,
     pub size: Option<i64>
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct VideoMessageType {
     pub body: String,
     pub info: Option<VideoInfo>,
-    pub url: String
+    pub url: MxcUri,
+
+    #[serde(rename = "m.new_content", skip_serializing_if = "Option::is_none")]
+    pub new_content: Option<Box<RoomMessageTypes>>,
+
+    #[serde(rename = "m.relates_to", skip_serializing_if = "Option::is_none")]
+    pub relates_to: Option<RelatesTo>
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct LocationMessageType {
     pub body: String,
     pub thumbnail_info: Option<ImageInfo>,
     pub geo_uri: String,
-    pub thumbnail_url: Option<String>
+    pub thumbnail_url: Option<MxcUri>,
+
+    #[serde(rename = "m.new_content", skip_serializing_if = "Option::is_none")]
+    pub new_content: Option<Box<RoomMessageTypes>>,
+
+    #[serde(rename = "m.relates_to", skip_serializing_if = "Option::is_none")]
+    pub relates_to: Option<RelatesTo>
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct FileInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub mimetype: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub size: Option<i64>
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct FileMessageType {
     pub body: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub info: Option<FileInfo>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub thumbnail_info: Option<ImageInfo>,
-    pub url: String,
+
+    pub url: MxcUri,
     pub filename: String,
-    pub thumbnail_url: Option<String>
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<MxcUri>,
+
+    #[serde(rename = "m.new_content", skip_serializing_if = "Option::is_none")]
+    pub new_content: Option<Box<RoomMessageTypes>>,
+
+    #[serde(rename = "m.relates_to", skip_serializing_if = "Option::is_none")]
+    pub relates_to: Option<RelatesTo>
+}
+
+// Text, emote and notice messages can carry a formatted_body next to the plain text body. The
+// only format the spec defines is HTML, which has to be sanitized before it's displayed.
+macro_rules! formatted_message_impls {
+    ($name:ident) => {
+        impl $name {
+            pub fn plain(body: &str) -> $name {
+                $name {
+                    body: String::from(body),
+                    format: None,
+                    formatted_body: None,
+                    new_content: None,
+                    relates_to: None
+                }
+            }
+
+            // Sanitizes the HTML and generates the plain text body from it
+            pub fn html(html: &str) -> $name {
+                let sanitized = sanitize_html(html);
+                $name::html_with_body(html_to_plaintext(sanitized.as_str()).as_str(), sanitized.as_str())
+            }
+
+            // Uses the given body as the plain text fallback; the HTML is still sanitized
+            pub fn html_with_body(body: &str, html: &str) -> $name {
+                $name {
+                    body: String::from(body),
+                    format: Some(String::from(HTML_FORMAT)),
+                    formatted_body: Some(sanitize_html(html)),
+                    new_content: None,
+                    relates_to: None
+                }
+            }
+
+            // Renders CommonMark, sticking to a plain message when there's no formatting to speak of
+            pub fn markdown(markdown: &str) -> $name {
+                let html = markdown_to_html(markdown);
+
+                if html.starts_with("<p>") && html.ends_with("</p>") && !html[3..html.len() - 4].contains('<') {
+                    $name::plain(decode_entities(&html[3..html.len() - 4]).as_str())
+                } else {
+                    $name::html_with_body(html_to_plaintext(html.as_str()).as_str(), html.as_str())
+                }
+            }
+
+            // The formatted body exactly as it was received, if it's HTML
+            pub fn html_body(&self) -> Option<&str> {
+                match (self.format.as_ref(), self.formatted_body.as_ref()) {
+                    (Some(format), Some(formatted_body)) if format == HTML_FORMAT => Some(formatted_body.as_str()),
+                    _ => None
+                }
+            }
+
+            // The formatted body made safe to display, if it's HTML. Reply fallbacks are dropped.
+            pub fn sanitized_html_body(&self) -> Option<String> {
+                self.html_body().map(sanitize_html)
+            }
+
+            // The body without the quote of the original message which replies start with
+            pub fn body_without_reply_fallback(&self) -> &str {
+                match self.relates_to.as_ref().and_then(RelatesTo::reply_to) {
+                    Some(_) => strip_reply_fallback(self.body.as_str()),
+                    None => self.body.as_str()
+                }
+            }
+
+            fn prefix_bodies(&mut self, prefix: &str) -> () {
+                self.body = format!("{}{}", prefix, self.body);
+                self.formatted_body = self.formatted_body.take().map(|html| format!("{}{}", escape_html(prefix), html));
+            }
+
+            // Puts a reply fallback in front of both bodies, turning the message into HTML if it isn't already
+            fn add_reply_fallback(&mut self, quote: &str, html_quote: &str) -> () {
+                let html = match self.html_body() {
+                    Some(html) => String::from(html),
+                    None => escape_html(self.body.as_str()).replace('\n', "<br>")
+                };
+
+                self.body = format!("{}\n\n{}", quote, self.body);
+                self.format = Some(String::from(HTML_FORMAT));
+                self.formatted_body = Some(format!("<mx-reply>{}</mx-reply>{}", html_quote, html));
+            }
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct TextMessageType {
-    pub body: String
+    pub body: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formatted_body: Option<String>,
+
+    #[serde(rename = "m.new_content", skip_serializing_if = "Option::is_none")]
+    pub new_content: Option<Box<RoomMessageTypes>>,
+
+    #[serde(rename = "m.relates_to", skip_serializing_if = "Option::is_none")]
+    pub relates_to: Option<RelatesTo>
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+formatted_message_impls!(TextMessageType);
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct EmoteMessageType {
-    pub body: String
+    pub body: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formatted_body: Option<String>,
+
+    #[serde(rename = "m.new_content", skip_serializing_if = "Option::is_none")]
+    pub new_content: Option<Box<RoomMessageTypes>>,
+
+    #[serde(rename = "m.relates_to", skip_serializing_if = "Option::is_none")]
+    pub relates_to: Option<RelatesTo>
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+formatted_message_impls!(EmoteMessageType);
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct NoticeMessageType {
-    pub body: String
+    pub body: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formatted_body: Option<String>,
+
+    #[serde(rename = "m.new_content", skip_serializing_if = "Option::is_none")]
+    pub new_content: Option<Box<RoomMessageTypes>>,
+
+    #[serde(rename = "m.relates_to", skip_serializing_if = "Option::is_none")]
+    pub relates_to: Option<RelatesTo>
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+formatted_message_impls!(NoticeMessageType);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ImageMessageType {
     pub body: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub info: Option<ImageInfo>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub thumbnail_info: Option<ImageInfo>,
-    pub url: String,
-    pub thumbnail_url: Option<String>,
+
+    pub url: MxcUri,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<MxcUri>,
+
+    #[serde(rename = "m.new_content", skip_serializing_if = "Option::is_none")]
+    pub new_content: Option<Box<RoomMessageTypes>>,
+
+    #[serde(rename = "m.relates_to", skip_serializing_if = "Option::is_none")]
+    pub relates_to: Option<RelatesTo>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct RoomJoinRulesEvent {
     pub join_rule: String
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct RoomCreateEvent {
-    pub creator: String,
-    #[serde(rename = "m.federate")]
+    pub creator: UserId,
+
+    #[serde(rename = "m.federate", skip_serializing_if = "Option::is_none")]
     pub federate: Option<bool>
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct ImageInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub mimetype: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub h: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub w: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub size: Option<i64>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct RoomAvatarEvent {
-    pub url: String,
+    pub url: MxcUri,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub info: Option<ImageInfo>,
-    pub thumbnail_url: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<MxcUri>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub thumbnail_info: Option<ImageInfo>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct RoomAliasEvent {
-    pub aliases: Vec<String>
+    #[serde(deserialize_with = "deserialize_valid_ids")]
+    pub aliases: Vec<RoomAliasId>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct RoomCanonicalAliasEvent {
-    pub alias: String
+    pub alias: RoomAliasId
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct RoomNameEvent {
     pub name: String
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct RoomTopicEvent {
     pub topic: String
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct TypingEvent {
-    pub user_ids: Vec<String>
+    pub user_ids: Vec<UserId>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct RoomPowerLevelsEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub events_default: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub invite: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub state_default: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub redact: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub ban: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub users_default: Option<i64>,
+
+    // Left off when empty so a partial power_level_content_override can't wipe these out
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub events: HashMap<String, i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub kick: Option<i64>,
-    pub users: HashMap<String, i64>
+
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub users: HashMap<UserId, i64>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct RoomHistoryVisibilityEvent {
     pub history_visibility: String
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct RoomMemberEvent {
     pub membership: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub avatar_url: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub displayname: Option<String>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct PresenceEvent {
-    pub user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<UserId>,
+
     pub presence: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub avatar_url: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub last_active_ago: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub currently_active: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub displayname: Option<String>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct State {
     pub events: Vec<Event>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct Presence {
     pub events: Vec<Event>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct InviteState {
     pub events: Vec<Event>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct AccountData {
     pub events: Vec<Event>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct Ephemeral {
     pub events: Vec<Event>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct InvitedState {
     pub events: Vec<Event>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct Timeline {
     pub limited: bool,
     pub prev_batch: Option<String>,
     pub events: Vec<Event>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct JoinedRoom {
     pub unread_notifications: Option<UnreadNotificationCounts>,
     pub timeline: Option<Timeline>,
@@ -443,323 +1265,2145 @@ pub struct JoinedRoom {
     pub ephemeral: Option<Ephemeral>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct LeftRoom {
     pub timeline: Option<Timeline>,
     pub state: Option<State>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct InvitedRoom {
     pub invite_state: Option<InviteState>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct Rooms {
-    pub leave: HashMap<String, LeftRoom>,
-    pub join: HashMap<String, JoinedRoom>,
-    pub invite: HashMap<String, InvitedRoom>
+    #[serde(deserialize_with = "deserialize_valid_id_keys")]
+    pub leave: HashMap<RoomId, LeftRoom>,
+
+    #[serde(deserialize_with = "deserialize_valid_id_keys")]
+    pub join: HashMap<RoomId, JoinedRoom>,
+
+    #[serde(deserialize_with = "deserialize_valid_id_keys")]
+    pub invite: HashMap<RoomId, InvitedRoom>
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct SyncResponse {
     pub next_batch: Option<String>,
     pub rooms: Rooms,
     pub presence: Presence
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug)]
 pub struct SendEventResponse {
-    pub event_id: String
+    pub event_id: EventId
 }
 
-pub struct MatrixClient {
-    access_token: Option<String>,
-    refresh_token: Option<String>,
-    local_device_id: Option<String>,
-    server_device_id: Option<String>,
-    user_id: Option<String>,
-    http_client: reqwest::Client,
-    homeserver: String
-}
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct EventFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i64>,
 
-#[derive(Debug)]
-pub enum MatrixClientError {
-    UrlError(reqwest::UrlError),
-    Http(reqwest::Error),
-    Io(::std::io::Error),
-    Json(serde_json::error::Error),
-    NotLoggedIn,
-    BadStatus(String),
-    BadRoomId(String)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_senders: Option<Vec<UserId>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_types: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub senders: Option<Vec<UserId>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub types: Option<Vec<String>>
 }
 
-const VERSION_URL: &'static str = "/_matrix/client/versions";
-const LOGIN_URL: &'static str = "/_matrix/client/r0/login";
-const LOGOUT_URL: &'static str = "/_matrix/client/r0/logout";
-const LOGOUT_ALL_URL: &'static str = "/_matrix/client/r0/logout/all";
-const PUBLIC_ROOM_URL: &'static str = "/_matrix/client/r0/publicRooms";
-const JOIN_ROOM_URL: &'static str = "/_matrix/client/r0/join/";
-const SYNC_URL: &'static str = "/_matrix/client/r0/sync";
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct RoomEventFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i64>,
 
-const SEND_ROOM_MESSAGE_PRE_ROOM_URL: &'static str = "/_matrix/client/r0/rooms/";
-const SEND_ROOM_MESSAGE_POST_ROOM_URL: &'static str = "/send/m.room.message/";
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_senders: Option<Vec<UserId>>,
 
-const TIMEOUT_DEFAULT_MS: u64 = 10000;
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_types: Option<Vec<String>>,
 
-impl MatrixClient {
-    pub fn new(homeserver: &str, device_id: Option<String>) -> MatrixClient {
-        let http_client = reqwest::Client::new();
-        MatrixClient {
-            access_token: None,
-            refresh_token: None,
-            local_device_id: device_id,
-            server_device_id: None,
-            user_id: None,
-            http_client: http_client,
-            homeserver: String::from(homeserver)
-        }
-    }
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub senders: Option<Vec<UserId>>,
 
-    fn get_access_token(&self) -> Result<String, MatrixClientError> {
-        let access_token = match self.access_token {
-            Some(ref x) => x.clone(),
-            None => { return Err(MatrixClientError::NotLoggedIn); }
-        };
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub types: Option<Vec<String>>,
 
-        Ok(access_token)
-    }
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_rooms: Option<Vec<RoomId>>,
 
-    pub fn get_supported_versions(&self) -> Result<VersionResponse, MatrixClientError> {
-        let mut request_url = String::with_capacity(self.homeserver.len() + VERSION_URL.len());
-        request_url.push_str(self.homeserver.as_str());
-        request_url.push_str(VERSION_URL);
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<RoomId>>,
 
-        let mut response = self.http_client.get(request_url.as_str()).send().map_err(MatrixClientError::Http)?;
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contains_url: Option<bool>,
 
-        let mut body = String::new();
-        response.read_to_string(&mut body).map_err(MatrixClientError::Io)?;
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lazy_load_members: Option<bool>,
 
-        if reqwest::StatusCode::OK != response.status() {
-            return Err(MatrixClientError::BadStatus(format!("Got error response from the server: {}; Contents: {}", response.status(), body)));
-        }
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_redundant_members: Option<bool>
+}
 
-        let version_repsonse: VersionResponse = serde_json::from_str(&body).map_err(MatrixClientError::Json)?;
+// The spec defines the state filter with exactly the same fields as a room event filter
+pub type StateFilter = RoomEventFilter;
 
-        Ok(version_repsonse)
-    }
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct RoomFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_rooms: Option<Vec<RoomId>>,
 
-    pub fn login(&mut self, user: &str, password: &str) -> Result<LoginResponse, MatrixClientError> {
-        let mut request_url = String::with_capacity(self.homeserver.len() + LOGIN_URL.len());
-        request_url.push_str(self.homeserver.as_str());
-        request_url.push_str(LOGIN_URL);
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<RoomId>>,
 
-        let login_request = LoginRequest {
-            password: String::from(password),
-            login_type: String::from("m.login.password"),
-            user: Some(String::from(user)),
-            medium: None,
-            address: None,
-            device_id: self.local_device_id.clone()
-        };
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ephemeral: Option<RoomEventFilter>,
 
-        let mut response = self.http_client.post(request_url.as_str())
-                                .json(&login_request)
-                                .send().map_err(MatrixClientError::Http)?;
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_leave: Option<bool>,
 
-        let mut body = String::new();
-        response.read_to_string(&mut body).map_err(MatrixClientError::Io)?;
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<StateFilter>,
 
-        if reqwest::StatusCode::OK != response.status() {
-            return Err(MatrixClientError::BadStatus(format!("Got error response from the server: {}; Contents: {}", response.status(), body)));
-        }
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeline: Option<RoomEventFilter>,
 
-        let login_response: LoginResponse = serde_json::from_str(&body).map_err(MatrixClientError::Json)?;
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_data: Option<RoomEventFilter>
+}
 
-        self.access_token = Some(login_response.access_token.clone());
-        self.refresh_token = login_response.refresh_token.clone();
-        self.server_device_id = login_response.device_id.clone();
-        self.user_id = Some(login_response.user_id.clone());
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Filter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_fields: Option<Vec<String>>,
 
-        Ok(login_response)
-    }
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_format: Option<String>,
 
-    pub fn logout(&mut self) -> Result<(), MatrixClientError> {
-        let mut request_url = String::with_capacity(self.homeserver.len() + LOGOUT_URL.len());
-        request_url.push_str(self.homeserver.as_str());
-        request_url.push_str(LOGOUT_URL);
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence: Option<EventFilter>,
 
-        let access_token = self.get_access_token()?;
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_data: Option<EventFilter>,
 
-        let mut response = self.http_client.post(request_url.as_str()).bearer_auth(access_token).send().map_err(MatrixClientError::Http)?;
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<RoomFilter>
+}
 
-        let mut body = String::new();
-        response.read_to_string(&mut body).map_err(MatrixClientError::Io)?;
+#[derive(Deserialize, Debug, Default)]
+pub struct CreateFilterResponse {
+    pub filter_id: String
+}
 
-        if reqwest::StatusCode::OK != response.status() {
-            return Err(MatrixClientError::BadStatus(format!("Got error response from the server: {}; Contents: {}", response.status(), body)));
-        }
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct RoomMessagesResponse {
+    pub start: String,
+    pub end: Option<String>,
+    pub chunk: Vec<Event>,
+    pub state: Option<Vec<Event>>
+}
 
-        self.access_token = None;
-        self.refresh_token = None;
-        self.server_device_id = None;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Forward,
+    Backward
+}
 
-        Ok(())
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Direction::Forward => "f",
+            Direction::Backward => "b"
+        }
     }
+}
 
-    pub fn logout_all(&mut self) -> Result<(), MatrixClientError> {
-        let mut request_url = String::with_capacity(self.homeserver.len() + LOGOUT_ALL_URL.len());
-        request_url.push_str(self.homeserver.as_str());
-        request_url.push_str(LOGOUT_ALL_URL);
-
-        let access_token = self.get_access_token()?;
+#[derive(Debug, Clone)]
+pub enum SyncFilter {
+    // The ID of a filter previously uploaded with create_filter
+    FilterId(String),
 
-        let mut response = self.http_client.post(request_url.as_str()).bearer_auth(access_token).send().map_err(MatrixClientError::Http)?;
+    // A filter which is sent along with the sync request itself
+    Inline(Filter)
+}
 
-        let mut body = String::new();
-        response.read_to_string(&mut body).map_err(MatrixClientError::Io)?;
+#[derive(Deserialize, Debug)]
+pub struct UploadResponse {
+    pub content_uri: MxcUri
+}
 
-        if reqwest::StatusCode::OK != response.status() {
-            return Err(MatrixClientError::BadStatus(format!("Got error response from the server: {}; Contents: {}", response.status(), body)));
-        }
+#[derive(Debug, Default)]
+pub struct MediaResponse {
+    pub content_type: Option<String>,
+    pub data: Vec<u8>
+}
 
-        self.access_token = None;
-        self.refresh_token = None;
-        self.server_device_id = None;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThumbnailMethod {
+    Crop,
+    Scale
+}
 
-        Ok(())
+impl ThumbnailMethod {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ThumbnailMethod::Crop => "crop",
+            ThumbnailMethod::Scale => "scale"
+        }
     }
+}
 
-    pub fn list_public_rooms(&self) -> Result<PublicRoomsResponse, MatrixClientError> {
-        let mut request_url = String::with_capacity(self.homeserver.len() + PUBLIC_ROOM_URL.len());
-        request_url.push_str(self.homeserver.as_str());
-        request_url.push_str(PUBLIC_ROOM_URL);
-
-        let access_token= self.get_access_token()?;
+// A local file read in and ready to be pushed to the media repository
+pub struct FileUpload {
+    pub filename: String,
+    pub content_type: &'static str,
+    pub data: Vec<u8>
+}
 
-        let mut response = self.http_client.get(request_url.as_str()).bearer_auth(access_token).send().map_err(MatrixClientError::Http)?;
+impl FileUpload {
+    pub fn read(path: &Path) -> Result<FileUpload, MatrixClientError> {
+        let filename = match path.file_name().and_then(|x| x.to_str()) {
+            Some(x) => String::from(x),
+            None => { return Err(MatrixClientError::BadPath(format!("Cannot determine a file name for {:?}", path))); }
+        };
 
-        let mut body = String::new();
-        response.read_to_string(&mut body).map_err(MatrixClientError::Io)?;
+        let mut data = Vec::new();
+        File::open(path).and_then(|mut f| f.read_to_end(&mut data)).map_err(MatrixClientError::Io)?;
 
-        if reqwest::StatusCode::OK != response.status() {
-            return Err(MatrixClientError::BadStatus(format!("Got error response from the server: {}; Contents: {}", response.status(), body)));
-        }
+        Ok(FileUpload {
+            filename: filename,
+            content_type: guess_content_type(path),
+            data: data
+        })
+    }
+}
 
-        let pub_rooms_response: PublicRoomsResponse = serde_json::from_str(&body).map_err(MatrixClientError::Json)?;
+// A local file which has been pushed to the media repository
+pub struct UploadedFile {
+    pub filename: String,
+    pub content_type: &'static str,
+    pub size: i64,
+    pub content_uri: MxcUri
+}
 
-        Ok(pub_rooms_response)
+impl UploadedFile {
+    pub fn into_image_message(self) -> ImageMessageType {
+        ImageMessageType {
+            body: self.filename,
+            info: Some(ImageInfo {
+                mimetype: Some(String::from(self.content_type)),
+                h: None,
+                w: None,
+                size: Some(self.size)
+            }),
+            thumbnail_info: None,
+            url: self.content_uri,
+            thumbnail_url: None,
+            new_content: None,
+            relates_to: None
+        }
     }
 
-    pub fn join_room(&self, room_id_or_alias: &String) -> Result<JoinResponse, MatrixClientError> {
-        if room_id_or_alias.is_empty() {
-            return Err(MatrixClientError::BadRoomId(String::from("Room ID or Alias cannot be empty!")));
+    pub fn into_file_message(self) -> FileMessageType {
+        FileMessageType {
+            body: self.filename.clone(),
+            info: Some(FileInfo {
+                mimetype: Some(String::from(self.content_type)),
+                size: Some(self.size)
+            }),
+            thumbnail_info: None,
+            url: self.content_uri,
+            filename: self.filename,
+            thumbnail_url: None,
+            new_content: None,
+            relates_to: None
         }
+    }
+}
+
+// A best-effort guess at a file's MIME type from its extension
+fn guess_content_type(path: &Path) -> &'static str {
+    let extension = match path.extension().and_then(|x| x.to_str()) {
+        Some(x) => x.to_lowercase(),
+        None => { return "application/octet-stream"; }
+    };
+
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "svg" => "image/svg+xml",
+        "txt" | "log" => "text/plain",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream"
+    }
+}
 
-        let mut request_url = String::with_capacity(self.homeserver.len() + JOIN_ROOM_URL.len() + room_id_or_alias.len());
-        request_url.push_str(self.homeserver.as_str());
-        request_url.push_str(JOIN_ROOM_URL);
-        request_url.push_str(room_id_or_alias.as_str());
+pub struct MatrixClient {
+    core: ClientCore,
+    transport: Box<dyn HttpTransport>,
+    timeouts: Timeouts,
+    timeouts_override: Option<Timeouts>,
+    retry_policy: RetryPolicy,
+    txn_ids: TransactionIds
+}
 
-        let access_token = self.get_access_token()?;
+#[derive(Debug)]
+pub enum MatrixClientError {
+    UrlError(reqwest::UrlError),
+    Http(reqwest::Error),
+    Io(::std::io::Error),
+    Json(serde_json::error::Error),
+    NotLoggedIn,
+    Timeout,
+    Matrix(MatrixError),
+    BadPath(String),
 
-        let mut response = self.http_client.post(request_url.as_str()).bearer_auth(access_token).send().map_err(MatrixClientError::Http)?;
+    // The event a reply or other relation was meant for came without an event_id
+    MissingEventId
+}
 
-        let mut body = String::new();
-        response.read_to_string(&mut body).map_err(MatrixClientError::Io)?;
+impl MatrixClient {
+    pub fn new(homeserver: &str, device_id: Option<String>) -> MatrixClient {
+        MatrixClient::with_transport(homeserver, device_id, Box::new(ReqwestTransport::new()))
+    }
 
-        if reqwest::StatusCode::OK != response.status() {
-            return Err(MatrixClientError::BadStatus(format!("Got error response from the server: {}; Contents: {}", response.status(), body)));
+    // Sends every request through `transport` instead of straight to the network
+    pub fn with_transport(homeserver: &str, device_id: Option<String>, transport: Box<dyn HttpTransport>) -> MatrixClient {
+        MatrixClient {
+            core: ClientCore::new(homeserver, device_id),
+            transport: transport,
+            timeouts: Timeouts::default(),
+            timeouts_override: None,
+            retry_policy: RetryPolicy::default(),
+            txn_ids: TransactionIds::new()
         }
+    }
 
-        let join_response: JoinResponse = serde_json::from_str(&body).map_err(MatrixClientError::Json)?;
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) -> () {
+        self.retry_policy = retry_policy;
+    }
 
-        Ok(join_response)
+    pub fn get_retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
     }
 
-    pub fn sync(&mut self, filter: Option<&str>, since: Option<&String>, full_state: Option<bool>, timeout_ms: Option<u64>) -> Result<SyncResponse, MatrixClientError> {
-        let mut request_url = {
-            let mut base_url = String::with_capacity(self.homeserver.len() + SYNC_URL.len());
-            base_url.push_str(self.homeserver.as_str());
-            base_url.push_str(SYNC_URL);
-            reqwest::Url::parse(base_url.as_str()).map_err(MatrixClientError::UrlError)?
-        };
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) -> () {
+        self.timeouts = timeouts;
+    }
 
-        match filter {
-            None => (),
-            Some(filter_string) => {
-                request_url.query_pairs_mut().append_pair("filter", filter_string);
-            }
-        };
-        
-        match since {
-            None => (),
-            Some(since_string) => {
-                request_url.query_pairs_mut().append_pair("since", since_string);
-            }
-        };
+    pub fn get_timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
 
-        match full_state {
-            None => (),
-            Some(full_state_string) => {
-                request_url.query_pairs_mut().append_pair("full_state", full_state_string.to_string().as_str());
-            }
-        };
+    // Runs `call` with `timeouts` used in place of the client's own for every request it makes
+    pub fn with_timeouts<T, F>(&mut self, timeouts: Timeouts, call: F) -> T
+        where F: FnOnce(&mut MatrixClient) -> T {
+        let previous = self.timeouts_override.take();
+        self.timeouts_override = Some(timeouts);
+        let result = call(self);
+        self.timeouts_override = previous;
+        result
+    }
 
-        let timeout = match timeout_ms {
-            None => Duration::from_millis(TIMEOUT_DEFAULT_MS),
-            Some(timeout_ms) => {
-                request_url.query_pairs_mut().append_pair("timeout", format!("{}", timeout_ms).as_str());
-                Duration::from_millis(timeout_ms + TIMEOUT_DEFAULT_MS)
-            }
+    fn effective_timeouts(&self) -> Timeouts {
+        self.timeouts_override.unwrap_or(self.timeouts)
+    }
+
+    // Sends the request, sending it again for as long as the retry policy and the total timeout allow
+    fn execute(&self, request: MatrixRequest) -> Result<HttpResponse, MatrixClientError> {
+        let timeouts = request.timeouts.unwrap_or(self.effective_timeouts());
+        let deadline = timeouts.total.map(|total| Instant::now() + total);
+
+        let mut attempt = 0;
+        loop {
+            let attempt_timeouts = match timeouts_for_attempt(&timeouts, deadline) {
+                Some(attempt_timeouts) => attempt_timeouts,
+                None => { return Err(MatrixClientError::Timeout); }
+            };
+
+            let delay = match self.transport.send(&request, &attempt_timeouts) {
+                Ok(response) => {
+                    match self.retry_policy.delay_for_response(&response, request.idempotency, attempt) {
+                        Some(delay) if fits_before_deadline(deadline, delay) => delay,
+                        _ => { return Ok(response); }
+                    }
+                },
+                Err(e) => {
+                    match self.retry_policy.delay_for_error(&e, request.idempotency, attempt) {
+                        Some(delay) if fits_before_deadline(deadline, delay) => delay,
+                        _ => { return Err(e); }
+                    }
+                }
+            };
+
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    pub fn get_supported_versions(&self) -> Result<VersionResponse, MatrixClientError> {
+        let response = self.execute(self.core.get_supported_versions_request()?)?;
+        parse_json_response(&response)
+    }
+
+    pub fn login(&mut self, user: &str, password: &str) -> Result<LoginResponse, MatrixClientError> {
+        let response = self.execute(self.core.login_request(user, password)?)?;
+        let login_response: LoginResponse = parse_json_response(&response)?;
+
+        self.core.apply_login(&login_response);
+
+        Ok(login_response)
+    }
+
+    pub fn logout(&mut self) -> Result<(), MatrixClientError> {
+        let response = self.execute(self.core.logout_request()?)?;
+        check_response(&response)?;
+
+        self.core.apply_logout();
+
+        Ok(())
+    }
+
+    pub fn logout_all(&mut self) -> Result<(), MatrixClientError> {
+        let response = self.execute(self.core.logout_all_request()?)?;
+        check_response(&response)?;
+
+        self.core.apply_logout();
+
+        Ok(())
+    }
+
+    pub fn list_public_rooms(&self) -> Result<PublicRoomsResponse, MatrixClientError> {
+        let response = self.execute(self.core.list_public_rooms_request()?)?;
+        parse_json_response(&response)
+    }
+
+    pub fn join_room(&self, room_id_or_alias: &RoomIdOrAliasId) -> Result<JoinResponse, MatrixClientError> {
+        let response = self.execute(self.core.join_room_request(room_id_or_alias)?)?;
+        parse_json_response(&response)
+    }
+
+    pub fn create_filter(&self, filter: &Filter) -> Result<CreateFilterResponse, MatrixClientError> {
+        let response = self.execute(self.core.create_filter_request(filter)?)?;
+        parse_json_response(&response)
+    }
+
+    pub fn get_filter(&self, filter_id: &str) -> Result<Filter, MatrixClientError> {
+        let response = self.execute(self.core.get_filter_request(filter_id)?)?;
+        parse_json_response(&response)
+    }
+
+    pub fn create_room(&self, create_room_request: &CreateRoomRequest) -> Result<CreateRoomResponse, MatrixClientError> {
+        let response = self.execute(self.core.create_room_request(create_room_request)?)?;
+        parse_json_response(&response)
+    }
+
+    pub fn leave_room(&self, room: &RoomId) -> Result<(), MatrixClientError> {
+        let response = self.execute(self.core.room_membership_request(room, "leave", &EmptyRequest {})?)?;
+        check_response(&response)
+    }
+
+    // Forgetting is only allowed once we have left the room
+    pub fn forget_room(&self, room: &RoomId) -> Result<(), MatrixClientError> {
+        let response = self.execute(self.core.room_membership_request(room, "forget", &EmptyRequest {})?)?;
+        check_response(&response)
+    }
+
+    pub fn invite_user(&self, room: &RoomId, user_id: &UserId) -> Result<(), MatrixClientError> {
+        let response = self.execute(self.core.room_membership_for_user_request(room, "invite", user_id, None)?)?;
+        check_response(&response)
+    }
+
+    pub fn kick_user(&self, room: &RoomId, user_id: &UserId, reason: Option<&str>) -> Result<(), MatrixClientError> {
+        let response = self.execute(self.core.room_membership_for_user_request(room, "kick", user_id, reason)?)?;
+        check_response(&response)
+    }
+
+    pub fn ban_user(&self, room: &RoomId, user_id: &UserId, reason: Option<&str>) -> Result<(), MatrixClientError> {
+        let response = self.execute(self.core.room_membership_for_user_request(room, "ban", user_id, reason)?)?;
+        check_response(&response)
+    }
+
+    pub fn unban_user(&self, room: &RoomId, user_id: &UserId) -> Result<(), MatrixClientError> {
+        let response = self.execute(self.core.room_membership_for_user_request(room, "unban", user_id, None)?)?;
+        check_response(&response)
+    }
+
+    pub fn sync(&mut self, filter: Option<&SyncFilter>, since: Option<&String>, full_state: Option<bool>, timeout_ms: Option<u64>) -> Result<SyncResponse, MatrixClientError> {
+        let request = self.core.sync_request(filter, since, full_state, timeout_ms, self.effective_timeouts())?;
+        let response = self.execute(request)?;
+        parse_json_response(&response)
+    }
+
+    pub fn room_messages(&self, room: &RoomId, from: &str, to: Option<&str>, dir: Direction, limit: Option<u64>, filter: Option<&RoomEventFilter>) -> Result<RoomMessagesResponse, MatrixClientError> {
+        let response = self.execute(self.core.room_messages_request(room, from, to, dir, limit, filter)?)?;
+        parse_json_response(&response)
+    }
+
+    // Walks a room's timeline one page at a time starting at `from`, yielding events in the
+    // order the server returns them (newest first when going backwards). Iteration stops once
+    // the server runs out of events or the `to` token is reached.
+    pub fn room_messages_iter<'a>(&'a self, room: &RoomId, from: &str, to: Option<&str>, dir: Direction, limit: Option<u64>, filter: Option<RoomEventFilter>) -> RoomMessagesIter<'a> {
+        RoomMessagesIter {
+            client: self,
+            room: room.clone(),
+            from: Some(String::from(from)),
+            to: to.map(String::from),
+            dir: dir,
+            limit: limit,
+            filter: filter,
+            events: VecDeque::new()
+        }
+    }
+
+    pub fn send_state_event<T: Serialize>(&self, room: &RoomId, event_type: &str, state_key: &str, content: &T) -> Result<SendEventResponse, MatrixClientError> {
+        let response = self.execute(self.core.send_state_event_request(room, event_type, state_key, content)?)?;
+        parse_json_response(&response)
+    }
+
+    pub fn get_state_event<T: DeserializeOwned>(&self, room: &RoomId, event_type: &str, state_key: &str) -> Result<T, MatrixClientError> {
+        let response = self.execute(self.core.get_state_event_request(room, event_type, state_key)?)?;
+        parse_json_response(&response)
+    }
+
+    pub fn get_room_state(&self, room: &RoomId) -> Result<Vec<Event>, MatrixClientError> {
+        let response = self.execute(self.core.get_room_state_request(room)?)?;
+        parse_json_response(&response)
+    }
+
+    pub fn upload_bytes(&self, data: Vec<u8>, content_type: &str, filename: Option<&str>) -> Result<UploadResponse, MatrixClientError> {
+        let response = self.execute(self.core.upload_request(data, content_type, filename)?)?;
+        parse_json_response(&response)
+    }
+
+    pub fn upload<R: Read>(&self, mut reader: R, content_type: &str, filename: Option<&str>) -> Result<UploadResponse, MatrixClientError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).map_err(MatrixClientError::Io)?;
+
+        self.upload_bytes(data, content_type, filename)
+    }
+
+    pub fn download(&self, mxc: &MxcUri) -> Result<MediaResponse, MatrixClientError> {
+        let response = self.execute(self.core.download_request(mxc)?)?;
+        parse_media_response(response)
+    }
+
+    pub fn thumbnail(&self, mxc: &MxcUri, width: u64, height: u64, method: ThumbnailMethod) -> Result<MediaResponse, MatrixClientError> {
+        let response = self.execute(self.core.thumbnail_request(mxc, width, height, method)?)?;
+        parse_media_response(response)
+    }
+
+    fn upload_file(&self, path: &Path) -> Result<UploadedFile, MatrixClientError> {
+        let file = FileUpload::read(path)?;
+        let size = file.data.len() as i64;
+
+        let upload_response = self.upload_bytes(file.data, file.content_type, Some(file.filename.as_str()))?;
+
+        Ok(UploadedFile {
+            filename: file.filename,
+            content_type: file.content_type,
+            size: size,
+            content_uri: upload_response.content_uri
+        })
+    }
+
+    pub fn image_message_from_file<P: AsRef<Path>>(&self, path: P) -> Result<ImageMessageType, MatrixClientError> {
+        Ok(self.upload_file(path.as_ref())?.into_image_message())
+    }
+
+    pub fn file_message_from_file<P: AsRef<Path>>(&self, path: P) -> Result<FileMessageType, MatrixClientError> {
+        Ok(self.upload_file(path.as_ref())?.into_file_message())
+    }
+
+    pub fn send_image_file<P: AsRef<Path>>(&self, room: &RoomId, path: P) -> Result<SendEventResponse, MatrixClientError> {
+        let image_message = self.image_message_from_file(path)?;
+
+        self.send_room_message(room, &RoomMessageTypes::ImageMessage(image_message))
+    }
+
+    pub fn send_file<P: AsRef<Path>>(&self, room: &RoomId, path: P) -> Result<SendEventResponse, MatrixClientError> {
+        let file_message = self.file_message_from_file(path)?;
+
+        self.send_room_message(room, &RoomMessageTypes::FileMessage(file_message))
+    }
+
+    pub fn new_transaction_id(&self) -> String {
+        self.txn_ids.next()
+    }
+
+    // Sending again with the same transaction ID will not create a second event, so callers that
+    // want to retry a send themselves should hang on to the ID they used the first time
+    pub fn send_room_event_with_txn_id<T: Serialize>(&self, room: &RoomId, event_type: &str, content: &T, txn_id: &str) -> Result<SendEventResponse, MatrixClientError> {
+        let response = self.execute(self.core.send_room_event_request(room, event_type, content, txn_id)?)?;
+        parse_json_response(&response)
+    }
+
+    pub fn send_room_event<T: Serialize>(&self, room: &RoomId, event_type: &str, content: &T) -> Result<SendEventResponse, MatrixClientError> {
+        let txn_id = self.new_transaction_id();
+        self.send_room_event_with_txn_id(room, event_type, content, txn_id.as_str())
+    }
+
+    pub fn send_room_message_with_txn_id(&self, room: &RoomId, message: &RoomMessageTypes, txn_id: &str) -> Result<SendEventResponse, MatrixClientError> {
+        self.send_room_event_with_txn_id(room, ROOM_MESSAGE_EVENT_TYPE, message, txn_id)
+    }
+
+    pub fn send_room_message(&self, room: &RoomId, message: &RoomMessageTypes) -> Result<SendEventResponse, MatrixClientError> {
+        self.send_room_event(room, ROOM_MESSAGE_EVENT_TYPE, message)
+    }
+
+    // Sends a text message written in CommonMark, with a plain text body generated from it
+    pub fn send_markdown_message(&self, room: &RoomId, markdown: &str) -> Result<SendEventResponse, MatrixClientError> {
+        self.send_room_message(room, &RoomMessageTypes::TextMessage(TextMessageType::markdown(markdown)))
+    }
+
+    pub fn send_markdown_notice(&self, room: &RoomId, markdown: &str) -> Result<SendEventResponse, MatrixClientError> {
+        self.send_room_message(room, &RoomMessageTypes::NoticeMessage(NoticeMessageType::markdown(markdown)))
+    }
+
+    // Sends `message` as a rich reply to `original`, quoting it for clients which don't show replies
+    pub fn send_reply(&self, room: &RoomId, original: &EventContainer<RoomMessageOptionType>, message: RoomMessageTypes) -> Result<SendEventResponse, MatrixClientError> {
+        let reply = message.into_reply(room, original)?;
+        self.send_room_message(room, &reply)
+    }
+
+    // Sends `message` into the thread starting at `thread_root`, as a fallback reply to `latest_event`
+    pub fn send_thread_message(&self, room: &RoomId, thread_root: &EventId, latest_event: &EventId, message: RoomMessageTypes) -> Result<SendEventResponse, MatrixClientError> {
+        self.send_room_message(room, &message.into_thread_message(thread_root, latest_event))
+    }
+
+    pub fn redact_with_txn_id(&self, room: &RoomId, event_id: &EventId, reason: Option<&str>, txn_id: &str) -> Result<SendEventResponse, MatrixClientError> {
+        let response = self.execute(self.core.redact_request(room, event_id, reason, txn_id)?)?;
+        parse_json_response(&response)
+    }
+
+    // Redactions are idempotent on their own transaction ID like any other event we send
+    pub fn redact(&self, room: &RoomId, event_id: &EventId, reason: Option<&str>) -> Result<SendEventResponse, MatrixClientError> {
+        let txn_id = self.new_transaction_id();
+        self.redact_with_txn_id(room, event_id, reason, txn_id.as_str())
+    }
+
+    // Reacts to an event with `key`, usually a single emoji
+    pub fn react(&self, room: &RoomId, event_id: &EventId, key: &str) -> Result<SendEventResponse, MatrixClientError> {
+        self.send_room_event(room, REACTION_EVENT_TYPE, &ReactionEvent::new(event_id, key))
+    }
+
+    // Replaces the content of one of our earlier messages with `new_content`
+    pub fn send_edit(&self, room: &RoomId, original: &EventId, new_content: RoomMessageTypes) -> Result<SendEventResponse, MatrixClientError> {
+        self.send_room_message(room, &new_content.into_edit(original))
+    }
+}
+
+
+pub struct RoomMessagesIter<'a> {
+    client: &'a MatrixClient,
+    room: RoomId,
+    from: Option<String>,
+    to: Option<String>,
+    dir: Direction,
+    limit: Option<u64>,
+    filter: Option<RoomEventFilter>,
+    events: VecDeque<Event>
+}
+
+impl<'a> RoomMessagesIter<'a> {
+    // The pagination token to resume from once the buffered events are used up
+    pub fn next_token(&self) -> Option<&String> {
+        self.from.as_ref()
+    }
+}
+
+impl<'a> Iterator for RoomMessagesIter<'a> {
+    type Item = Result<Event, MatrixClientError>;
+
+    fn next(&mut self) -> Option<Result<Event, MatrixClientError>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Some(Ok(event));
+            }
+
+            let from = match self.from.take() {
+                Some(x) => x,
+                None => { return None; }
+            };
+
+            let page = match self.client.room_messages(&self.room, from.as_str(), self.to.as_ref().map(String::as_str), self.dir, self.limit, self.filter.as_ref()) {
+                Ok(x) => x,
+                Err(e) => { return Some(Err(e)); }
+            };
+
+            if page.chunk.is_empty() {
+                return None;
+            }
+
+            // A missing or unchanged end token means this was the last page
+            match page.end {
+                Some(end) => {
+                    if end != from {
+                        self.from = Some(end);
+                    }
+                },
+                None => ()
+            };
+
+            self.events.extend(page.chunk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use fake_homeserver::*;
+
+    const ROOM: &'static str = "!room:fake.example.org";
+
+    fn room() -> RoomId {
+        RoomId::parse(ROOM).unwrap()
+    }
+
+    fn text(body: &str) -> RoomMessageTypes {
+        RoomMessageTypes::TextMessage(TextMessageType::plain(body))
+    }
+
+    fn busy_room_filter() -> Filter {
+        Filter {
+            presence: Some(EventFilter {
+                types: Some(vec![]),
+                .. EventFilter::default()
+            }),
+            room: Some(RoomFilter {
+                timeline: Some(RoomEventFilter {
+                    limit: Some(10),
+                    .. RoomEventFilter::default()
+                }),
+                state: Some(StateFilter {
+                    lazy_load_members: Some(true),
+                    .. StateFilter::default()
+                }),
+                .. RoomFilter::default()
+            }),
+            .. Filter::default()
+        }
+    }
+
+    #[test]
+    fn filters_leave_out_what_is_not_set() {
+        assert_eq!(serde_json::to_value(&Filter::default()).unwrap(), serde_json::json!({}));
+        assert_eq!(serde_json::to_value(&busy_room_filter()).unwrap(), serde_json::json!({
+            "presence": { "types": [] },
+            "room": {
+                "timeline": { "limit": 10 },
+                "state": { "lazy_load_members": true }
+            }
+        }));
+    }
+
+    #[test]
+    fn filters_from_the_server_are_parsed() {
+        let filter: Filter = serde_json::from_str(r#"{
+            "event_format": "client",
+            "event_fields": ["type", "content.body"],
+            "room": {
+                "not_rooms": ["!noisy:example.org"],
+                "include_leave": false,
+                "state": { "lazy_load_members": true, "include_redundant_members": false },
+                "timeline": { "limit": 10, "not_senders": ["@spam:example.org"], "contains_url": true }
+            }
+        }"#).unwrap();
+
+        assert_eq!(filter.event_format, Some(String::from("client")));
+        let room = filter.room.unwrap();
+        assert_eq!(room.not_rooms, Some(vec![RoomId::parse("!noisy:example.org").unwrap()]));
+        assert_eq!(room.state.unwrap().lazy_load_members, Some(true));
+        let timeline = room.timeline.unwrap();
+        assert_eq!(timeline.limit, Some(10));
+        assert_eq!(timeline.contains_url, Some(true));
+        assert!(filter.presence.is_none());
+    }
+
+    #[test]
+    fn created_filter_ids_are_parsed() {
+        let response: CreateFilterResponse = serde_json::from_str(r#"{"filter_id": "66696p746572"}"#).unwrap();
+
+        assert_eq!(response.filter_id, "66696p746572");
+    }
+
+    #[test]
+    fn power_levels_leave_out_what_is_not_set() {
+        let mut power_levels = RoomPowerLevelsEvent::default();
+        power_levels.ban = Some(50);
+        power_levels.users.insert(UserId::parse("@alice:example.org").unwrap(), 100);
+
+        assert_eq!(serde_json::to_value(&power_levels).unwrap(), serde_json::json!({
+            "ban": 50,
+            "users": { "@alice:example.org": 100 }
+        }));
+    }
+
+    #[test]
+    fn room_state_is_parsed_into_events() {
+        let state: Vec<Event> = serde_json::from_str(r#"[
+            {"type": "m.room.topic", "state_key": "", "sender": "@alice:example.org", "event_id": "$1", "origin_server_ts": 1, "content": {"topic": "Crates"}},
+            {"type": "m.room.history_visibility", "state_key": "", "sender": "@alice:example.org", "event_id": "$2", "origin_server_ts": 2, "content": {"history_visibility": "shared"}}
+        ]"#).unwrap();
+
+        assert_eq!(state.len(), 2);
+        match state[0] {
+            Event::RoomTopic(ref event) => assert_eq!(event.content.topic, "Crates"),
+            ref other => panic!("expected a topic, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn membership_requests_only_carry_a_reason_when_given() {
+        let request = RoomMembershipRequest {
+            user_id: UserId::parse("@spam:example.org").unwrap(),
+            reason: None
+        };
+        assert_eq!(serde_json::to_value(&request).unwrap(), serde_json::json!({ "user_id": "@spam:example.org" }));
+
+        let request = RoomMembershipRequest {
+            user_id: UserId::parse("@spam:example.org").unwrap(),
+            reason: Some(String::from("Spamming"))
+        };
+        assert_eq!(serde_json::to_value(&request).unwrap(), serde_json::json!({ "user_id": "@spam:example.org", "reason": "Spamming" }));
+    }
+
+    #[test]
+    fn create_room_requests_carry_everything_asked_for() {
+        let mut power_levels = RoomPowerLevelsEvent::default();
+        power_levels.invite = Some(0);
+
+        let request = CreateRoomRequest {
+            visibility: Some(RoomVisibility::Private),
+            preset: Some(RoomPreset::TrustedPrivateChat),
+            room_alias_name: Some(String::from("crates")),
+            name: Some(String::from("Crates")),
+            topic: Some(String::from("Crate updates")),
+            invite: Some(vec![UserId::parse("@alice:example.org").unwrap()]),
+            is_direct: Some(false),
+            initial_state: Some(vec![InitialStateEvent::new("m.room.history_visibility", "", &RoomHistoryVisibilityEvent {
+                history_visibility: String::from("joined")
+            }).unwrap()]),
+            power_level_content_override: Some(power_levels),
+            room_version: Some(String::from("5"))
+        };
+
+        assert_eq!(serde_json::to_value(&request).unwrap(), serde_json::json!({
+            "visibility": "private",
+            "preset": "trusted_private_chat",
+            "room_alias_name": "crates",
+            "name": "Crates",
+            "topic": "Crate updates",
+            "invite": ["@alice:example.org"],
+            "is_direct": false,
+            "initial_state": [{
+                "type": "m.room.history_visibility",
+                "state_key": "",
+                "content": { "history_visibility": "joined" }
+            }],
+            "power_level_content_override": { "invite": 0 },
+            "room_version": "5"
+        }));
+    }
+
+    #[test]
+    fn create_room_requests_leave_out_defaults() {
+        assert_eq!(serde_json::to_value(&CreateRoomRequest::default()).unwrap(), serde_json::json!({}));
+
+        let response: CreateRoomResponse = serde_json::from_str(r#"{"room_id": "!new:example.org"}"#).unwrap();
+        assert_eq!(response.room_id, "!new:example.org");
+    }
+
+    #[test]
+    fn content_types_are_guessed_from_the_extension() {
+        assert_eq!(guess_content_type(Path::new("cat.PNG")), "image/png");
+        assert_eq!(guess_content_type(Path::new("notes.txt")), "text/plain");
+        assert_eq!(guess_content_type(Path::new("archive.tar.gz")), "application/gzip");
+        assert_eq!(guess_content_type(Path::new("Makefile")), "application/octet-stream");
+    }
+
+    #[test]
+    fn media_messages_leave_out_what_is_not_set() {
+        let image = ImageMessageType {
+            body: String::from("cat.png"),
+            info: Some(ImageInfo {
+                mimetype: Some(String::from("image/png")),
+                h: None,
+                w: None,
+                size: Some(4)
+            }),
+            thumbnail_info: None,
+            url: MxcUri::parse("mxc://example.org/cat").unwrap(),
+            thumbnail_url: None,
+            new_content: None,
+            relates_to: None
+        };
+
+        assert_eq!(serde_json::to_value(&image).unwrap(), serde_json::json!({
+            "body": "cat.png",
+            "info": { "mimetype": "image/png", "size": 4 },
+            "url": "mxc://example.org/cat"
+        }));
+
+        let file = FileMessageType {
+            body: String::from("notes.txt"),
+            info: None,
+            thumbnail_info: None,
+            url: MxcUri::parse("mxc://example.org/notes").unwrap(),
+            filename: String::from("notes.txt"),
+            thumbnail_url: None,
+            new_content: None,
+            relates_to: None
+        };
+
+        assert_eq!(serde_json::to_value(&file).unwrap(), serde_json::json!({
+            "body": "notes.txt",
+            "url": "mxc://example.org/notes",
+            "filename": "notes.txt"
+        }));
+    }
+
+    #[test]
+    fn login_token_is_used_for_later_requests() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.client();
+
+        let login = client.login("jacobian", "hunter2").unwrap();
+        assert_eq!(login.user_id, homeserver.user_id());
+
+        client.join_room(&room().into()).unwrap();
+
+        let joins = homeserver.requests_to("join");
+        assert_eq!(joins.len(), 1);
+        assert_eq!(joins[0].access_token, Some(login.access_token));
+        assert_eq!(homeserver.joined_rooms(), vec![String::from(ROOM)]);
+    }
+
+    #[test]
+    fn requests_before_login_fail_without_being_sent() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.client();
+
+        match client.join_room(&room().into()) {
+            Err(MatrixClientError::NotLoggedIn) => (),
+            other => panic!("expected NotLoggedIn, got {:?}", other)
+        };
+        assert!(homeserver.requests().is_empty());
+    }
+
+    #[test]
+    fn bad_password_is_a_forbidden_matrix_error() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.client();
+
+        match client.login("jacobian", "wrong") {
+            Err(MatrixClientError::Matrix(e)) => {
+                assert_eq!(e.status, reqwest::StatusCode::FORBIDDEN);
+                assert!(e.is(ErrorCode::Forbidden));
+            },
+            other => panic!("expected a Matrix error, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn aliases_are_sent_escaped_and_resolved() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+
+        let joined = client.join_room(&RoomIdOrAliasId::parse("#lobby:fake.example.org").unwrap()).unwrap();
+
+        assert_eq!(joined.room_id, "!lobby:fake.example.org");
+        assert_eq!(homeserver.requests_to("join")[0].path, "join/#lobby:fake.example.org");
+    }
+
+    #[test]
+    fn filters_are_uploaded_for_the_logged_in_user_and_fetched_back() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+
+        let created = client.create_filter(&busy_room_filter()).unwrap();
+        let fetched = client.get_filter(created.filter_id.as_str()).unwrap();
+
+        let requests = homeserver.requests_to("user");
+        assert_eq!(requests[0].method, reqwest::Method::POST);
+        assert_eq!(requests[0].path, "user/@jacobian:fake.example.org/filter");
+        assert_eq!(requests[0].body, Some(serde_json::json!({
+            "presence": { "types": [] },
+            "room": {
+                "timeline": { "limit": 10 },
+                "state": { "lazy_load_members": true }
+            }
+        })));
+        assert_eq!(requests[1].method, reqwest::Method::GET);
+        assert_eq!(requests[1].path, format!("user/@jacobian:fake.example.org/filter/{}", created.filter_id));
+
+        let room_filter = fetched.room.unwrap();
+        assert_eq!(room_filter.timeline.unwrap().limit, Some(10));
+        assert_eq!(room_filter.state.unwrap().lazy_load_members, Some(true));
+        assert!(room_filter.rooms.is_none());
+    }
+
+    #[test]
+    fn unknown_filters_are_not_found() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+
+        match client.get_filter("42") {
+            Err(MatrixClientError::Matrix(e)) => assert_eq!(e.errcode, Some(ErrorCode::NotFound)),
+            other => panic!("expected a Matrix error, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn sync_takes_a_filter_id_or_an_inline_filter() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_client();
+
+        client.sync(Some(&SyncFilter::FilterId(String::from("7"))), None, None, None).unwrap();
+        client.sync(Some(&SyncFilter::Inline(busy_room_filter())), None, None, None).unwrap();
+        client.sync(None, None, None, None).unwrap();
+
+        let syncs = homeserver.requests_to("sync");
+        assert_eq!(syncs[0].query.get("filter"), Some(&String::from("7")));
+        let inline: serde_json::Value = serde_json::from_str(syncs[1].query["filter"].as_str()).unwrap();
+        assert_eq!(inline["room"]["state"]["lazy_load_members"], true);
+        assert!(syncs[2].query.get("filter").is_none());
+    }
+
+    #[test]
+    fn state_with_an_empty_key_leaves_it_off_the_path() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        let topic = RoomTopicEvent { topic: String::from("Incident 42") };
+
+        let sent = client.send_state_event(&room(), "m.room.topic", "", &topic).unwrap();
+        let fetched: RoomTopicEvent = client.get_state_event(&room(), "m.room.topic", "").unwrap();
+
+        assert_eq!(sent.event_id, "$state0:fake.example.org");
+        assert_eq!(fetched.topic, "Incident 42");
+
+        let requests = homeserver.requests_to("rooms");
+        assert_eq!(requests[0].method, reqwest::Method::PUT);
+        assert_eq!(requests[0].path, "rooms/!room:fake.example.org/state/m.room.topic");
+        assert_eq!(requests[0].body, Some(serde_json::json!({ "topic": "Incident 42" })));
+        assert_eq!(requests[1].method, reqwest::Method::GET);
+        assert_eq!(requests[1].path, "rooms/!room:fake.example.org/state/m.room.topic");
+    }
+
+    #[test]
+    fn state_keys_are_sent_as_the_last_path_segment() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        let content = serde_json::json!({ "enabled": true });
+
+        client.send_state_event(&room(), "org.example.flag", "@alice:fake.example.org", &content).unwrap();
+        let fetched: serde_json::Value = client.get_state_event(&room(), "org.example.flag", "@alice:fake.example.org").unwrap();
+
+        assert_eq!(fetched, content);
+        assert_eq!(homeserver.requests_to("rooms")[0].path, "rooms/!room:fake.example.org/state/org.example.flag/@alice:fake.example.org");
+        assert!(client.get_state_event::<serde_json::Value>(&room(), "org.example.flag", "").is_err());
+    }
+
+    #[test]
+    fn room_state_is_fetched_as_events() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        client.send_state_event(&room(), "m.room.name", "", &RoomNameEvent { name: String::from("Incidents") }).unwrap();
+        client.send_state_event(&room(), "m.room.topic", "", &RoomTopicEvent { topic: String::from("Old") }).unwrap();
+        client.send_state_event(&room(), "m.room.topic", "", &RoomTopicEvent { topic: String::from("New") }).unwrap();
+
+        let state = client.get_room_state(&room()).unwrap();
+
+        assert_eq!(homeserver.requests().last().unwrap().path, "rooms/!room:fake.example.org/state");
+        assert_eq!(state.len(), 2);
+        match state[0] {
+            Event::RoomName(ref event) => {
+                assert_eq!(event.content.name, "Incidents");
+                assert_eq!(event.state_key, Some(String::new()));
+            },
+            ref other => panic!("expected a room name, got {:?}", other)
+        };
+        match state[1] {
+            Event::RoomTopic(ref event) => assert_eq!(event.content.topic, "New"),
+            ref other => panic!("expected a room topic, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn missing_state_is_not_found() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+
+        match client.get_state_event::<RoomTopicEvent>(&room(), "m.room.topic", "") {
+            Err(MatrixClientError::Matrix(e)) => assert_eq!(e.errcode, Some(ErrorCode::NotFound)),
+            other => panic!("expected a Matrix error, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn rooms_can_be_forgotten_once_left() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        client.join_room(&room().into()).unwrap();
+
+        match client.forget_room(&room()) {
+            Err(MatrixClientError::Matrix(e)) => assert_eq!(e.errcode, Some(ErrorCode::Unknown)),
+            other => panic!("expected a Matrix error, got {:?}", other)
+        };
+
+        client.leave_room(&room()).unwrap();
+        client.forget_room(&room()).unwrap();
+
+        assert!(homeserver.joined_rooms().is_empty());
+        let requests = homeserver.requests_to("rooms");
+        let paths: Vec<&str> = requests.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec![
+            "rooms/!room:fake.example.org/forget",
+            "rooms/!room:fake.example.org/leave",
+            "rooms/!room:fake.example.org/forget"
+        ]);
+        assert_eq!(requests[1].body, Some(serde_json::json!({})));
+    }
+
+    #[test]
+    fn moderation_is_sent_with_the_user_and_reason() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        let alice = UserId::parse("@alice:fake.example.org").unwrap();
+
+        client.invite_user(&room(), &alice).unwrap();
+        client.kick_user(&room(), &alice, Some("Spamming")).unwrap();
+        client.ban_user(&room(), &alice, None).unwrap();
+        client.unban_user(&room(), &alice).unwrap();
+
+        let requests = homeserver.requests_to("rooms");
+        let sent: Vec<(&str, Option<serde_json::Value>)> = requests.iter().map(|r| (r.path.as_str(), r.body.clone())).collect();
+        assert_eq!(sent, vec![
+            ("rooms/!room:fake.example.org/invite", Some(serde_json::json!({ "user_id": "@alice:fake.example.org" }))),
+            ("rooms/!room:fake.example.org/kick", Some(serde_json::json!({ "user_id": "@alice:fake.example.org", "reason": "Spamming" }))),
+            ("rooms/!room:fake.example.org/ban", Some(serde_json::json!({ "user_id": "@alice:fake.example.org" }))),
+            ("rooms/!room:fake.example.org/unban", Some(serde_json::json!({ "user_id": "@alice:fake.example.org" })))
+        ]);
+        assert!(requests.iter().all(|r| r.method == reqwest::Method::POST));
+    }
+
+    #[test]
+    fn refused_moderation_is_a_matrix_error() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        homeserver.respond_with(reqwest::Method::POST, "rooms", FakeResponse::error(403, "M_FORBIDDEN", "You don't have permission to ban"));
+
+        match client.ban_user(&room(), &UserId::parse("@alice:fake.example.org").unwrap(), Some("Spamming")) {
+            Err(MatrixClientError::Matrix(e)) => {
+                assert_eq!(e.status, reqwest::StatusCode::FORBIDDEN);
+                assert_eq!(e.errcode, Some(ErrorCode::Forbidden));
+            },
+            other => panic!("expected a Matrix error, got {:?}", other)
+        };
+        // A refusal isn't worth retrying
+        assert_eq!(homeserver.requests_to("rooms").len(), 1);
+    }
+
+    #[test]
+    fn rooms_are_created_with_everything_asked_for() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        let mut power_levels = RoomPowerLevelsEvent::default();
+        power_levels.ban = Some(50);
+        let power_levels_json = serde_json::to_value(&power_levels).unwrap();
+
+        let created = client.create_room(&CreateRoomRequest {
+            visibility: Some(RoomVisibility::Private),
+            preset: Some(RoomPreset::TrustedPrivateChat),
+            room_alias_name: Some(String::from("incident-42")),
+            name: Some(String::from("Incident 42")),
+            topic: Some(String::from("The database is on fire")),
+            invite: Some(vec![UserId::parse("@alice:fake.example.org").unwrap()]),
+            is_direct: Some(false),
+            initial_state: Some(vec![InitialStateEvent::new("m.room.guest_access", "", &GuestAccessEvent { guest_access: GuestAccess::CanJoin }).unwrap()]),
+            power_level_content_override: Some(power_levels),
+            room_version: Some(String::from("5"))
+        }).unwrap();
+
+        assert_eq!(created.room_id, "!new1:fake.example.org");
+        assert_eq!(homeserver.joined_rooms(), vec![String::from("!new1:fake.example.org")]);
+
+        let request = &homeserver.requests_to("createRoom")[0];
+        assert_eq!(request.method, reqwest::Method::POST);
+        assert_eq!(request.body, Some(serde_json::json!({
+            "visibility": "private",
+            "preset": "trusted_private_chat",
+            "room_alias_name": "incident-42",
+            "name": "Incident 42",
+            "topic": "The database is on fire",
+            "invite": ["@alice:fake.example.org"],
+            "is_direct": false,
+            "initial_state": [
+                { "type": "m.room.guest_access", "state_key": "", "content": { "guest_access": "can_join" } }
+            ],
+            "power_level_content_override": power_levels_json,
+            "room_version": "5"
+        })));
+        assert_eq!(request.body.as_ref().unwrap()["power_level_content_override"]["ban"], 50);
+    }
+
+    #[test]
+    fn rooms_can_be_created_with_nothing_but_defaults() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+
+        client.create_room(&CreateRoomRequest::default()).unwrap();
+        let second = client.create_room(&CreateRoomRequest::default()).unwrap();
+
+        assert_eq!(second.room_id, "!new2:fake.example.org");
+        assert_eq!(homeserver.requests_to("createRoom")[0].body, Some(serde_json::json!({})));
+    }
+
+    #[test]
+    fn uploads_can_be_downloaded_again() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+
+        let uploaded = client.upload_bytes(b"\x89PNG".to_vec(), "image/png", Some("cat picture.png")).unwrap();
+        let downloaded = client.download(&uploaded.content_uri).unwrap();
+
+        assert_eq!(uploaded.content_uri.to_string(), "mxc://fake.example.org/media0");
+        assert_eq!(downloaded.content_type, Some(String::from("image/png")));
+        assert_eq!(downloaded.data, b"\x89PNG".to_vec());
+
+        let requests = homeserver.requests();
+        let upload = &requests[requests.len() - 2];
+        assert_eq!(upload.method, reqwest::Method::POST);
+        assert_eq!(upload.path, "upload");
+        assert_eq!(upload.query.get("filename"), Some(&String::from("cat picture.png")));
+        assert_eq!(upload.content_type, Some(String::from("image/png")));
+        assert_eq!(upload.raw_body, Some(b"\x89PNG".to_vec()));
+        let download = &requests[requests.len() - 1];
+        assert_eq!(download.method, reqwest::Method::GET);
+        assert_eq!(download.path, "download/fake.example.org/media0");
+    }
+
+    #[test]
+    fn uploads_from_a_reader_need_no_filename() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+
+        client.upload(&b"some notes"[..], "text/plain", None).unwrap();
+
+        let upload = &homeserver.requests_to("upload")[0];
+        assert!(upload.query.get("filename").is_none());
+        assert_eq!(upload.raw_body, Some(b"some notes".to_vec()));
+    }
+
+    #[test]
+    fn thumbnails_ask_for_a_size_and_method() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        let uploaded = client.upload_bytes(b"\x89PNG".to_vec(), "image/png", None).unwrap();
+
+        let thumbnail = client.thumbnail(&uploaded.content_uri, 64, 32, ThumbnailMethod::Crop).unwrap();
+        client.thumbnail(&uploaded.content_uri, 640, 480, ThumbnailMethod::Scale).unwrap();
+
+        assert_eq!(thumbnail.content_type, Some(String::from("image/png")));
+        let requests = homeserver.requests_to("thumbnail");
+        assert_eq!(requests[0].path, "thumbnail/fake.example.org/media0");
+        let queries: Vec<(&str, &str, &str)> = requests.iter().map(|r| (r.query["width"].as_str(), r.query["height"].as_str(), r.query["method"].as_str())).collect();
+        assert_eq!(queries, vec![("64", "32", "crop"), ("640", "480", "scale")]);
+    }
+
+    #[test]
+    fn missing_media_is_not_found() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+
+        match client.download(&MxcUri::parse("mxc://fake.example.org/nothing").unwrap()) {
+            Err(MatrixClientError::Matrix(e)) => assert_eq!(e.errcode, Some(ErrorCode::NotFound)),
+            other => panic!("expected a Matrix error, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn local_files_are_uploaded_and_sent_as_messages() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        let path = ::std::env::temp_dir().join(format!("jacobian-test-{}-report.pdf", ::std::process::id()));
+        ::std::fs::write(&path, b"%PDF-1.4").unwrap();
+
+        client.send_file(&room(), &path).unwrap();
+        let image = client.image_message_from_file(&path).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+
+        let filename = path.file_name().unwrap().to_str().unwrap();
+        let upload = &homeserver.requests_to("upload")[0];
+        assert_eq!(upload.query.get("filename"), Some(&String::from(filename)));
+        assert_eq!(upload.content_type, Some(String::from("application/pdf")));
+
+        let sent = &homeserver.sent_events()[0];
+        assert_eq!(sent.content, serde_json::json!({
+            "msgtype": "m.file",
+            "body": filename,
+            "filename": filename,
+            "url": "mxc://fake.example.org/media0",
+            "info": { "mimetype": "application/pdf", "size": 8 }
+        }));
+
+        assert_eq!(image.url, "mxc://fake.example.org/media1");
+        assert_eq!(image.info.unwrap().size, Some(8));
+    }
+
+    #[test]
+    fn paths_without_a_file_name_are_refused() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+
+        match client.send_image_file(&room(), "/") {
+            Err(MatrixClientError::BadPath(_)) => (),
+            other => panic!("expected BadPath, got {:?}", other)
+        };
+        assert!(homeserver.requests_to("upload").is_empty());
+    }
+
+    #[test]
+    fn rate_limited_sends_wait_and_retry() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_client();
+        client.set_retry_policy(quick_retries());
+        homeserver.respond_with(reqwest::Method::PUT, "rooms", FakeResponse::rate_limited(1));
+
+        let sent = client.send_room_message(&room(), &text("hi")).unwrap();
+
+        let sends = homeserver.requests_to("rooms");
+        assert_eq!(sends.len(), 2);
+        assert_eq!(sends[0].path, sends[1].path);
+        assert_eq!(sent.event_id, homeserver.sent_events()[0].event_id.as_str());
+    }
+
+    #[test]
+    fn limit_exceeded_is_retried_whatever_the_status() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_client();
+        client.set_retry_policy(quick_retries());
+        homeserver.respond_with(reqwest::Method::PUT, "rooms", FakeResponse::error(400, "M_LIMIT_EXCEEDED", "slow down"));
+
+        client.send_room_message(&room(), &text("hi")).unwrap();
+
+        assert_eq!(homeserver.requests_to("rooms").len(), 2);
+        assert_eq!(homeserver.sent_events().len(), 1);
+    }
+
+    #[test]
+    fn server_errors_on_sends_are_retried_with_the_same_transaction_id() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_client();
+        client.set_retry_policy(quick_retries());
+        homeserver.respond_with(reqwest::Method::PUT, "rooms", FakeResponse::error(502, "M_UNKNOWN", "bad gateway"));
+
+        client.send_room_message(&room(), &text("hi")).unwrap();
+
+        let sends = homeserver.requests_to("rooms");
+        assert_eq!(sends.len(), 2);
+        assert_eq!(sends[0].path, sends[1].path);
+        assert_eq!(homeserver.sent_events().len(), 1);
+    }
+
+    #[test]
+    fn server_errors_on_non_idempotent_requests_are_not_retried() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_client();
+        client.set_retry_policy(quick_retries());
+        homeserver.respond_with(reqwest::Method::POST, "createRoom", FakeResponse::error(500, "M_UNKNOWN", "oops"));
+
+        match client.create_room(&CreateRoomRequest::default()) {
+            Err(MatrixClientError::Matrix(e)) => assert_eq!(e.status, reqwest::StatusCode::INTERNAL_SERVER_ERROR),
+            other => panic!("expected a Matrix error, got {:?}", other)
+        };
+        assert_eq!(homeserver.requests_to("createRoom").len(), 1);
+    }
+
+    #[test]
+    fn resending_with_a_transaction_id_does_not_duplicate() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        let txn_id = client.new_transaction_id();
+
+        let first = client.send_room_message_with_txn_id(&room(), &text("once"), txn_id.as_str()).unwrap();
+        let second = client.send_room_message_with_txn_id(&room(), &text("once"), txn_id.as_str()).unwrap();
+
+        assert_eq!(first.event_id, second.event_id);
+        assert_eq!(homeserver.sent_events().len(), 1);
+    }
+
+    #[test]
+    fn timeouts_are_reported_as_such() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_client();
+        homeserver.respond_with(reqwest::Method::GET, "sync", FakeResponse::Timeout);
+
+        match client.sync(None, None, None, Some(30000)) {
+            Err(MatrixClientError::Timeout) => (),
+            other => panic!("expected Timeout, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn timeouts_can_be_overridden_for_one_call() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_client();
+        let timeouts = Timeouts {
+            connect: Some(Duration::from_secs(1)),
+            read: Some(Duration::from_secs(2)),
+            total: None
+        };
+
+        client.with_timeouts(timeouts, |client| client.get_supported_versions()).unwrap();
+        client.get_supported_versions().unwrap();
+
+        let requests = homeserver.requests_to("versions");
+        assert_eq!(requests[0].timeouts, timeouts);
+        assert_eq!(requests[1].timeouts, Timeouts::default());
+    }
+
+    #[test]
+    fn sync_timeouts_leave_room_for_the_long_poll() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_client();
+
+        client.sync(None, None, None, Some(30000)).unwrap();
+        client.sync(None, None, None, None).unwrap();
+
+        let requests = homeserver.requests_to("sync");
+        assert_eq!(requests[0].timeouts.read, Some(Duration::from_millis(40000)));
+        assert_eq!(requests[0].timeouts.connect, Timeouts::default().connect);
+        assert_eq!(requests[1].timeouts, Timeouts::default());
+    }
+
+    #[test]
+    fn attempts_are_cut_short_by_the_total_timeout() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_client();
+        client.set_retry_policy(quick_retries());
+        client.set_timeouts(Timeouts {
+            total: Some(Duration::from_millis(300)),
+            .. Timeouts::default()
+        });
+        let unavailable = FakeResponse::error(503, "M_UNKNOWN", "Try again");
+        homeserver.respond_with(reqwest::Method::GET, "versions", FakeResponse::slow(Duration::from_millis(200), unavailable.clone()));
+        homeserver.respond_with(reqwest::Method::GET, "versions", FakeResponse::slow(Duration::from_millis(200), unavailable));
+
+        let started = Instant::now();
+        match client.get_supported_versions() {
+            Err(MatrixClientError::Timeout) => (),
+            other => panic!("expected Timeout, got {:?}", other)
+        };
+
+        assert!(started.elapsed() < Duration::from_millis(400));
+        let requests = homeserver.requests_to("versions");
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].timeouts.request_timeout().unwrap() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn no_attempt_is_made_once_the_total_timeout_has_passed() {
+        let timeouts = Timeouts {
+            total: Some(Duration::from_secs(5)),
+            .. Timeouts::default()
+        };
+
+        assert_eq!(timeouts_for_attempt(&timeouts, Some(Instant::now())), None);
+        assert_eq!(timeouts_for_attempt(&timeouts, None), Some(timeouts));
+
+        let attempt_timeouts = timeouts_for_attempt(&timeouts, Some(Instant::now() + Duration::from_secs(1))).unwrap();
+        assert!(attempt_timeouts.total.unwrap() <= Duration::from_secs(1));
+        assert!(attempt_timeouts.connect.unwrap() <= Duration::from_secs(1));
+        assert!(attempt_timeouts.request_timeout().unwrap() <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn logout_forgets_the_access_token() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_client();
+
+        client.logout().unwrap();
+
+        assert!(!homeserver.is_logged_in());
+        match client.join_room(&room().into()) {
+            Err(MatrixClientError::NotLoggedIn) => (),
+            other => panic!("expected NotLoggedIn, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn sync_parses_timeline_messages() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let mut client = homeserver.logged_in_client();
+        homeserver.push_messages(ROOM, &[("@alice:fake.example.org", "hello")]);
+
+        let sync = client.sync(None, None, None, None).unwrap();
+
+        assert_eq!(sync.next_batch, Some(String::from("s1")));
+        let events = &sync.rooms.join[ROOM].timeline.as_ref().unwrap().events;
+        match events[0] {
+            Event::RoomMessage(ref msg) => {
+                assert_eq!(msg.sender, Some(UserId::parse("@alice:fake.example.org").unwrap()));
+                match msg.content {
+                    RoomMessageOptionType::Message(RoomMessageTypes::TextMessage(ref txt)) => assert_eq!(txt.body, "hello"),
+                    ref other => panic!("expected a text message, got {:?}", other)
+                }
+            },
+            ref other => panic!("expected a room message, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn unknown_event_types_keep_their_type_and_json() {
+        let json = r#"{"type": "m.room.encrypted", "sender": "@alice:fake.example.org", "content": {"algorithm": "m.megolm.v1.aes-sha2", "ciphertext": "abc"}}"#;
+
+        match serde_json::from_str::<Event>(json).unwrap() {
+            Event::Unknown(unknown) => {
+                assert_eq!(unknown.event_type, "m.room.encrypted");
+                assert_eq!(unknown.json, serde_json::from_str::<serde_json::Value>(json).unwrap());
+            },
+            other => panic!("expected an unknown event, got {:?}", other)
         };
+    }
+
+    #[test]
+    fn malformed_known_events_are_kept_as_unknown() {
+        let json = r#"{"type": "m.room.topic", "content": {"topic": 42}}"#;
+
+        match serde_json::from_str::<Event>(json).unwrap() {
+            Event::Unknown(unknown) => {
+                assert_eq!(unknown.event_type, "m.room.topic");
+                assert_eq!(unknown.json["content"]["topic"], 42);
+            },
+            other => panic!("expected an unknown event, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn one_odd_event_does_not_spoil_the_sync() {
+        let json = r#"{
+            "next_batch": "s1",
+            "rooms": {
+                "join": {
+                    "!room:fake.example.org": {
+                        "timeline": {
+                            "limited": false,
+                            "events": [
+                                {"type": "org.example.custom", "content": {"anything": [1, 2, 3]}},
+                                {"content": {"body": "no type at all"}},
+                                {"type": "m.room.message", "sender": "@alice:fake.example.org", "content": {"msgtype": "m.text", "body": "hi"}}
+                            ]
+                        }
+                    }
+                },
+                "invite": {},
+                "leave": {}
+            },
+            "presence": {"events": []}
+        }"#;
+
+        let sync: SyncResponse = serde_json::from_str(json).unwrap();
+
+        let events = &sync.rooms.join[ROOM].timeline.as_ref().unwrap().events;
+        assert_eq!(events.len(), 3);
+        match events[0] {
+            Event::Unknown(ref unknown) => assert_eq!(unknown.event_type, "org.example.custom"),
+            ref other => panic!("expected an unknown event, got {:?}", other)
+        };
+        match events[1] {
+            Event::Unknown(ref unknown) => assert_eq!(unknown.event_type, ""),
+            ref other => panic!("expected an unknown event, got {:?}", other)
+        };
+        match events[2] {
+            Event::RoomMessage(_) => (),
+            ref other => panic!("expected a room message, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn one_odd_room_id_does_not_spoil_the_sync() {
+        let json = r#"{
+            "next_batch": "s1",
+            "rooms": {
+                "join": {
+                    "not a room id": {},
+                    "!room:fake.example.org": {}
+                },
+                "invite": {"": {}},
+                "leave": {}
+            },
+            "presence": {"events": []}
+        }"#;
+
+        let sync: SyncResponse = serde_json::from_str(json).unwrap();
+
+        assert_eq!(sync.rooms.join.keys().collect::<Vec<_>>(), vec![&room()]);
+        assert!(sync.rooms.invite.is_empty());
+    }
 
-        let access_token = self.get_access_token()?;
+    #[test]
+    fn public_rooms_keep_their_valid_aliases() {
+        let json = r##"{
+            "chunk": [{
+                "world_readable": true,
+                "topic": null,
+                "num_joined_members": 2,
+                "avatar_url": "",
+                "room_id": "!room:fake.example.org",
+                "guest_can_join": false,
+                "aliases": ["#lobby:fake.example.org", "lobby"],
+                "name": "Lobby"
+            }]
+        }"##;
+
+        let rooms: PublicRoomsResponse = serde_json::from_str(json).unwrap();
+
+        assert_eq!(rooms.chunk[0].aliases, vec![RoomAliasId::parse("#lobby:fake.example.org").unwrap()]);
+    }
+
+    fn round_trip<T: Serialize + DeserializeOwned + PartialEq + ::std::fmt::Debug>(value: &T) -> () {
+        let json = serde_json::to_string(value).unwrap();
+        let back: T = serde_json::from_str(json.as_str()).unwrap();
+        assert_eq!(&back, value, "{} didn't round trip", json);
+    }
+
+    fn round_trip_json<T: Serialize + DeserializeOwned + PartialEq + ::std::fmt::Debug>(json: &str) -> T {
+        let value: T = serde_json::from_str(json).unwrap();
+        round_trip(&value);
+        value
+    }
+
+    #[test]
+    fn state_content_round_trips() {
+        round_trip(&RoomTopicEvent { topic: String::from("Things") });
+        round_trip(&RoomNameEvent { name: String::from("A room") });
+        round_trip(&RoomMemberEvent { membership: String::from("join"), avatar_url: None, displayname: Some(String::from("Alice")) });
+        round_trip(&RoomCreateEvent { creator: UserId::parse("@alice:fake.example.org").unwrap(), federate: Some(false) });
+        round_trip(&RoomJoinRulesEvent { join_rule: String::from("invite") });
+        round_trip(&RoomHistoryVisibilityEvent { history_visibility: String::from("shared") });
+        round_trip(&RoomAliasEvent { aliases: vec![RoomAliasId::parse("#a:fake.example.org").unwrap()] });
+        round_trip(&RoomCanonicalAliasEvent { alias: RoomAliasId::parse("#a:fake.example.org").unwrap() });
+        round_trip(&GuestAccessEvent { guest_access: GuestAccess::CanJoin });
+        round_trip(&RoomRedactionEvent { reason: Some(String::from("spam")) });
+
+        let mut power_levels = RoomPowerLevelsEvent::default();
+        power_levels.ban = Some(50);
+        power_levels.users.insert(UserId::parse("@alice:fake.example.org").unwrap(), 100);
+        power_levels.events.insert(String::from("m.room.name"), 50);
+        round_trip(&power_levels);
+    }
+
+    #[test]
+    fn member_content_leaves_out_missing_fields() {
+        let member = RoomMemberEvent { membership: String::from("leave"), avatar_url: None, displayname: None };
+        assert_eq!(serde_json::to_value(&member).unwrap(), serde_json::json!({ "membership": "leave" }));
+    }
+
+    #[test]
+    fn message_content_round_trips() {
+        round_trip(&text("hello"));
+        round_trip(&RoomMessageTypes::EmoteMessage(EmoteMessageType::plain("waves")));
+        round_trip(&RoomMessageTypes::NoticeMessage(NoticeMessageType::html("<b>beep</b>")));
+        round_trip(&RoomMessageOptionType::Message(text("hello")));
+        round_trip(&RoomMessageOptionType::Redacted(RedactedMessageContent {}));
+    }
+
+    #[test]
+    fn events_round_trip() {
+        round_trip_json::<Event>(r#"{"type": "m.room.message", "sender": "@alice:fake.example.org", "origin_server_ts": 1550000000000,
+                                     "content": {"msgtype": "m.text", "body": "hi"}, "unsigned": {"age": 12, "transaction_id": "t1"}}"#);
+        round_trip_json::<Event>(r#"{"type": "m.room.member", "sender": "@alice:fake.example.org", "state_key": "@alice:fake.example.org",
+                                     "content": {"membership": "join", "displayname": "Alice"},
+                                     "unsigned": {"age": 5, "prev_content": {"membership": "invite"}}}"#);
+        round_trip_json::<Event>(r#"{"type": "m.room.message", "sender": "@alice:fake.example.org", "content": {},
+                                     "unsigned": {"age": 1, "redacted_by": "$r", "redacted_because": {"content": {"reason": "spam"}, "sender": "@mod:fake.example.org"}}}"#);
+        round_trip_json::<Event>(r#"{"type": "m.typing", "content": {"user_ids": ["@alice:fake.example.org"]}}"#);
+        round_trip_json::<Event>(r#"{"type": "m.receipt", "content": {"$e": {"m.read": {"@alice:fake.example.org": {"ts": 1}}}}}"#);
+        round_trip_json::<Event>(r#"{"type": "m.presence", "sender": "@alice:fake.example.org", "content": {"presence": "online", "currently_active": true}}"#);
+    }
 
-        let mut response = self.http_client.get(request_url).bearer_auth(access_token).send().map_err(MatrixClientError::Http)?; //.timeout(timeout);
+    #[test]
+    fn unknown_events_are_written_back_unchanged() {
+        let json = serde_json::json!({ "type": "org.example.custom", "content": { "nested": [1, { "a": null }] }, "extra": true });
 
-        let mut body = String::new();
-        response.read_to_string(&mut body).map_err(MatrixClientError::Io)?;
+        let event: Event = serde_json::from_value(json.clone()).unwrap();
+
+        assert_eq!(serde_json::to_value(&event).unwrap(), json);
+        round_trip(&event);
+    }
+
+    #[test]
+    fn sync_responses_round_trip() {
+        let sync = round_trip_json::<SyncResponse>(r#"{
+            "next_batch": "s72595_4483_1934",
+            "rooms": {
+                "join": {
+                    "!room:fake.example.org": {
+                        "unread_notifications": {"highlight_count": 1, "notification_count": 5},
+                        "state": {"events": [{"type": "m.room.topic", "state_key": "", "content": {"topic": "Things"}}]},
+                        "timeline": {
+                            "limited": true,
+                            "prev_batch": "t34-23535_0_0",
+                            "events": [
+                                {"type": "m.room.message", "sender": "@alice:fake.example.org", "content": {"msgtype": "m.text", "body": "hi"}},
+                                {"type": "m.room.encrypted", "content": {"ciphertext": "abc"}}
+                            ]
+                        },
+                        "ephemeral": {"events": [{"type": "m.typing", "content": {"user_ids": []}}]},
+                        "account_data": {"events": []}
+                    }
+                },
+                "invite": {
+                    "!invited:fake.example.org": {"invite_state": {"events": [{"type": "m.room.name", "state_key": "", "content": {"name": "Invited"}}]}}
+                },
+                "leave": {
+                    "!left:fake.example.org": {"timeline": {"limited": false, "events": []}}
+                }
+            },
+            "presence": {"events": []}
+        }"#);
+
+        assert_eq!(sync.rooms.join[ROOM].timeline.as_ref().unwrap().events.len(), 2);
+    }
+
+    #[test]
+    fn event_ids_and_timestamps_are_exposed() {
+        let json = r#"{"type": "m.room.message", "event_id": "$abc:fake.example.org", "room_id": "!room:fake.example.org",
+                       "sender": "@alice:fake.example.org", "origin_server_ts": 1550000000123,
+                       "content": {"msgtype": "m.text", "body": "hi"}}"#;
+
+        match round_trip_json::<Event>(json) {
+            Event::RoomMessage(ref msg) => {
+                assert_eq!(msg.event_id().unwrap(), "$abc:fake.example.org");
+                assert_eq!(msg.room_id().unwrap(), ROOM);
+                assert_eq!(msg.sender().unwrap(), "@alice:fake.example.org");
+                assert_eq!(msg.state_key(), None);
+                assert_eq!(msg.redacts(), None);
+                assert_eq!(msg.timestamp(), Some(Utc.ymd(2019, 2, 12).and_hms_milli(19, 33, 20, 123)));
+            },
+            other => panic!("expected a room message, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn redactions_say_what_they_redact() {
+        let json = r#"{"type": "m.room.redaction", "event_id": "$r:fake.example.org", "redacts": "$abc:fake.example.org",
+                       "sender": "@mod:fake.example.org", "content": {"reason": "spam"}}"#;
+
+        match round_trip_json::<Event>(json) {
+            Event::RoomRedaction(ref redaction) => {
+                assert_eq!(redaction.redacts().unwrap(), "$abc:fake.example.org");
+                assert_eq!(redaction.timestamp(), None);
+            },
+            other => panic!("expected a redaction, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn state_keys_are_exposed() {
+        let json = r#"{"type": "m.room.member", "state_key": "@alice:fake.example.org", "content": {"membership": "join"}}"#;
+
+        match round_trip_json::<Event>(json) {
+            Event::RoomMember(ref member) => assert_eq!(member.state_key(), Some("@alice:fake.example.org")),
+            other => panic!("expected a member event, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn formatted_messages_are_parsed() {
+        let json = r#"{"type": "m.room.message", "sender": "@alice:fake.example.org",
+                       "content": {"msgtype": "m.text", "body": "hi there", "format": "org.matrix.custom.html",
+                                   "formatted_body": "<b onclick=\"x()\">hi</b> there<script>x()</script>"}}"#;
+
+        match round_trip_json::<Event>(json) {
+            Event::RoomMessage(ref msg) => match msg.content {
+                RoomMessageOptionType::Message(RoomMessageTypes::TextMessage(ref txt)) => {
+                    assert_eq!(txt.body, "hi there");
+                    assert_eq!(txt.html_body(), Some("<b onclick=\"x()\">hi</b> there<script>x()</script>"));
+                    assert_eq!(txt.sanitized_html_body().unwrap(), "<b>hi</b> there");
+                },
+                ref other => panic!("expected a text message, got {:?}", other)
+            },
+            other => panic!("expected a room message, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn unknown_formats_are_not_treated_as_html() {
+        let notice = NoticeMessageType { body: String::from("hi"), format: Some(String::from("org.example.other")), formatted_body: Some(String::from("<b>hi</b>")), .. NoticeMessageType::default() };
+        assert_eq!(notice.html_body(), None);
+        assert_eq!(NoticeMessageType::plain("hi").html_body(), None);
+    }
+
+    #[test]
+    fn html_messages_are_sent_with_a_plaintext_body() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+
+        let message = TextMessageType::html("<p>Hello <em>world</em></p><ul><li>one</li></ul>");
+        client.send_room_message(&room(), &RoomMessageTypes::TextMessage(message)).unwrap();
+
+        assert_eq!(homeserver.sent_events()[0].content, serde_json::json!({
+            "msgtype": "m.text",
+            "body": "Hello world\n\n- one",
+            "format": "org.matrix.custom.html",
+            "formatted_body": "<p>Hello <em>world</em></p><ul><li>one</li></ul>"
+        }));
+    }
+
+    #[test]
+    fn markdown_without_formatting_is_sent_plain() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+
+        client.send_markdown_message(&room(), "just words & nothing\nelse").unwrap();
+        client.send_markdown_notice(&room(), "1. first\n2. second").unwrap();
+
+        let sent = homeserver.sent_events();
+        assert_eq!(sent[0].content, serde_json::json!({ "msgtype": "m.text", "body": "just words & nothing\nelse" }));
+        assert_eq!(sent[1].content, serde_json::json!({
+            "msgtype": "m.notice",
+            "body": "1. first\n2. second",
+            "format": "org.matrix.custom.html",
+            "formatted_body": "<ol>\n<li>first</li>\n<li>second</li>\n</ol>"
+        }));
+    }
 
-        if reqwest::StatusCode::OK != response.status() {
-            return Err(MatrixClientError::BadStatus(format!("Got error response from the server: {}; Contents: {}", response.status(), body)));
+    fn message_event(json: serde_json::Value) -> EventContainer<RoomMessageOptionType> {
+        match serde_json::from_value(json).unwrap() {
+            Event::RoomMessage(message) => message,
+            other => panic!("expected a room message, got {:?}", other)
         }
+    }
+
+    #[test]
+    fn replies_quote_the_original() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        let original = message_event(serde_json::json!({
+            "type": "m.room.message", "event_id": "$orig:fake.example.org", "sender": "@alice:fake.example.org",
+            "content": { "msgtype": "m.text", "body": "first\nsecond", "format": "org.matrix.custom.html", "formatted_body": "first<br><b>second</b>" }
+        }));
+
+        client.send_reply(&room(), &original, text("a <reply>")).unwrap();
+
+        assert_eq!(homeserver.sent_events()[0].content, serde_json::json!({
+            "msgtype": "m.text",
+            "body": "> <@alice:fake.example.org> first\n> second\n\na <reply>",
+            "format": "org.matrix.custom.html",
+            "formatted_body": "<mx-reply><blockquote><a href=\"https://matrix.to/#/!room:fake.example.org/$orig:fake.example.org\">In reply to</a> \
+                               <a href=\"https://matrix.to/#/@alice:fake.example.org\">@alice:fake.example.org</a><br>first<br><b>second</b></blockquote></mx-reply>a &lt;reply&gt;",
+            "m.relates_to": { "m.in_reply_to": { "event_id": "$orig:fake.example.org" } }
+        }));
+    }
+
+    #[test]
+    fn replies_to_replies_do_not_nest_the_quotes() {
+        let original = message_event(serde_json::json!({
+            "type": "m.room.message", "event_id": "$second:fake.example.org", "sender": "@bob:fake.example.org",
+            "content": {
+                "msgtype": "m.text",
+                "body": "> <@alice:fake.example.org> first\n\nsecond",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<mx-reply><blockquote>first</blockquote></mx-reply>second",
+                "m.relates_to": { "m.in_reply_to": { "event_id": "$first:fake.example.org" } }
+            }
+        }));
+
+        let reply = text("third").into_reply(&room(), &original).unwrap();
+
+        match reply {
+            RoomMessageTypes::TextMessage(ref txt) => {
+                assert_eq!(txt.body, "> <@bob:fake.example.org> second\n\nthird");
+                assert_eq!(txt.body_without_reply_fallback(), "third");
+                assert_eq!(txt.sanitized_html_body().unwrap(), "third");
+            },
+            other => panic!("expected a text message, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn replying_to_malformed_html_does_not_panic() {
+        let original = message_event(serde_json::json!({
+            "type": "m.room.message", "event_id": "$orig:fake.example.org", "sender": "@alice:fake.example.org",
+            "content": { "msgtype": "m.text", "body": "jacobian: say hi", "format": "org.matrix.custom.html", "formatted_body": "<a href=\"https://x" }
+        }));
+
+        assert!(text("hi").into_reply(&room(), &original).is_ok());
+    }
+
+    #[test]
+    fn replies_need_an_event_id() {
+        let original = message_event(serde_json::json!({ "type": "m.room.message", "content": { "msgtype": "m.text", "body": "hi" } }));
+
+        match text("reply").into_reply(&room(), &original) {
+            Err(MatrixClientError::MissingEventId) => (),
+            other => panic!("expected MissingEventId, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn thread_relations_are_parsed() {
+        let root = EventId::parse("$root:fake.example.org").unwrap();
+        let latest = EventId::parse("$latest:fake.example.org").unwrap();
+
+        let in_thread = round_trip_json::<RelatesTo>(r#"{"rel_type": "m.thread", "event_id": "$root:fake.example.org",
+                                                          "m.in_reply_to": {"event_id": "$latest:fake.example.org"}, "is_falling_back": true}"#);
+        assert_eq!(in_thread, RelatesTo::thread(&root, &latest));
+        assert_eq!(in_thread.thread_root(), Some(&root));
+        assert_eq!(in_thread.reply_to(), None);
+
+        let thread_reply = RelatesTo::thread_reply(&root, &latest);
+        assert_eq!(thread_reply.thread_root(), Some(&root));
+        assert_eq!(thread_reply.reply_to(), Some(&latest));
+
+        let reply = RelatesTo::reply(&latest);
+        assert_eq!(reply.thread_root(), None);
+        assert_eq!(reply.reply_to(), Some(&latest));
+    }
+
+    #[test]
+    fn thread_messages_are_sent_with_the_relation() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        let root = EventId::parse("$root:fake.example.org").unwrap();
+
+        client.send_thread_message(&room(), &root, &root, text("in a thread")).unwrap();
+
+        let content = &homeserver.sent_events()[0].content;
+        assert_eq!(content["body"], "in a thread");
+        assert_eq!(content["m.relates_to"]["rel_type"], "m.thread");
+    }
 
-        let sync_response: SyncResponse = serde_json::from_str(&body).map_err(MatrixClientError::Json)?;
+    #[test]
+    fn edits_carry_the_new_content_and_a_fallback() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        let original = client.send_room_message(&room(), &text("build in progress")).unwrap();
+
+        let new_content = RoomMessageTypes::NoticeMessage(NoticeMessageType::markdown("build **passed**"));
+        client.send_edit(&room(), &original.event_id, new_content).unwrap();
+
+        assert_eq!(homeserver.sent_events()[1].content, serde_json::json!({
+            "msgtype": "m.notice",
+            "body": "* build passed",
+            "format": "org.matrix.custom.html",
+            "formatted_body": "* <p>build <strong>passed</strong></p>",
+            "m.new_content": {
+                "msgtype": "m.notice",
+                "body": "build passed",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<p>build <strong>passed</strong></p>"
+            },
+            "m.relates_to": { "rel_type": "m.replace", "event_id": original.event_id.as_str() }
+        }));
+    }
 
-        Ok(sync_response)
+    #[test]
+    fn edits_are_linked_to_the_original() {
+        let edit = message_event(serde_json::json!({
+            "type": "m.room.message", "event_id": "$edit:fake.example.org", "sender": "@alice:fake.example.org",
+            "content": {
+                "msgtype": "m.text",
+                "body": "* fixed",
+                "m.new_content": { "msgtype": "m.text", "body": "fixed" },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": "$orig:fake.example.org" }
+            }
+        }));
+
+        assert_eq!(edit.content.replaces().unwrap(), "$orig:fake.example.org");
+        match edit.content {
+            RoomMessageOptionType::Message(ref message) => {
+                assert_eq!(message.new_content(), Some(&text("fixed")));
+                assert_eq!(message.relates_to().unwrap().thread_root(), None);
+            },
+            ref other => panic!("expected a message, got {:?}", other)
+        };
+        round_trip(&edit.content);
     }
 
-    pub fn send_room_message(&mut self, room: &str, message: &RoomMessageTypes) -> Result<SendEventResponse, MatrixClientError> {
-        let mut request_url = reqwest::Url::parse(self.homeserver.as_str()).map_err(MatrixClientError::UrlError)?;
+    #[test]
+    fn edits_leave_out_the_old_relation() {
+        let root = EventId::parse("$root:fake.example.org").unwrap();
+        let original = EventId::parse("$orig:fake.example.org").unwrap();
 
+        let edit = text("again").into_thread_message(&root, &root).into_edit(&original);
 
-        let mut url_path = String::with_capacity(SEND_ROOM_MESSAGE_PRE_ROOM_URL.len() + SEND_ROOM_MESSAGE_POST_ROOM_URL.len() + room.len());
-        url_path.push_str(SEND_ROOM_MESSAGE_PRE_ROOM_URL);
-        url_path.push_str(room);
-        url_path.push_str(SEND_ROOM_MESSAGE_POST_ROOM_URL);
+        assert_eq!(edit.replaces(), Some(&original));
+        assert_eq!(edit.new_content().unwrap().relates_to(), None);
+        assert_eq!(edit.body(), Some("* again"));
+    }
 
-        let now = Utc::now();
-        url_path.push_str(now.format("%s.%.3f").to_string().as_str());
-        let url_path = url_path.replace(":", "%3A");
-        request_url.set_path(url_path.as_str());
+    #[test]
+    fn reactions_are_sent_as_annotations() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        let target = EventId::parse("$target:fake.example.org").unwrap();
 
-        println!("Request URL: {:?}", request_url);
+        client.react(&room(), &target, "\u{1f44d}").unwrap();
 
-        let access_token = self.get_access_token()?;
+        let sent = &homeserver.sent_events()[0];
+        assert_eq!(sent.event_type, "m.reaction");
+        assert_eq!(sent.content, serde_json::json!({
+            "m.relates_to": { "rel_type": "m.annotation", "event_id": "$target:fake.example.org", "key": "\u{1f44d}" }
+        }));
+    }
 
-        let mut response = self.http_client.put(request_url).json(message).bearer_auth(access_token).send().map_err(MatrixClientError::Http)?;
+    #[test]
+    fn reaction_events_are_parsed() {
+        let json = r#"{"type": "m.reaction", "event_id": "$r:fake.example.org", "sender": "@alice:fake.example.org",
+                       "content": {"m.relates_to": {"rel_type": "m.annotation", "event_id": "$target:fake.example.org", "key": "x"}}}"#;
+
+        match round_trip_json::<Event>(json) {
+            Event::Reaction(ref reaction) => {
+                assert_eq!(reaction.content.reacts_to().unwrap(), "$target:fake.example.org");
+                assert_eq!(reaction.content.key(), Some("x"));
+            },
+            other => panic!("expected a reaction, got {:?}", other)
+        };
 
-        let mut body = String::new();
-        response.read_to_string(&mut body).map_err(MatrixClientError::Io)?;
+        match round_trip_json::<Event>(r#"{"type": "m.reaction", "content": {}}"#) {
+            Event::Reaction(ref reaction) => assert_eq!(reaction.content.reacts_to(), None),
+            other => panic!("expected a redacted reaction, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn redactions_are_sent_with_their_own_transaction_ids() {
+        let homeserver = FakeHomeserver::new("jacobian", "hunter2");
+        let client = homeserver.logged_in_client();
+        let sent = client.send_room_message(&room(), &text("oops")).unwrap();
+
+        client.redact(&room(), &sent.event_id, Some("typo")).unwrap();
+        client.redact(&room(), &sent.event_id, None).unwrap();
+
+        let redactions: Vec<SentEvent> = homeserver.sent_events().into_iter().filter(|e| e.event_type == "m.room.redaction").collect();
+        assert_eq!(redactions.len(), 2);
+        assert_eq!(redactions[0].redacts.as_ref().map(String::as_str), Some(sent.event_id.as_str()));
+        assert_eq!(redactions[0].content, serde_json::json!({ "reason": "typo" }));
+        assert_eq!(redactions[1].content, serde_json::json!({}));
+        assert_ne!(redactions[0].txn_id, redactions[1].txn_id);
+        assert_ne!(redactions[0].txn_id, homeserver.sent_events()[0].txn_id);
+    }
 
-        if reqwest::StatusCode::OK != response.status() {
-            return Err(MatrixClientError::BadStatus(format!("Got error response from the server: {}; Contents: {}", response.status(), body)));
+    fn redaction_of(event_id: &str) -> EventContainer<RoomRedactionEvent> {
+        match serde_json::from_value(serde_json::json!({
+            "type": "m.room.redaction", "event_id": "$redaction:fake.example.org", "redacts": event_id,
+            "sender": "@mod:fake.example.org", "content": { "reason": "spam" }
+        })).unwrap() {
+            Event::RoomRedaction(redaction) => redaction,
+            other => panic!("expected a redaction, got {:?}", other)
         }
+    }
 
-        let send_event_response: SendEventResponse = serde_json::from_str(&body).map_err(MatrixClientError::Json)?;
+    #[test]
+    fn redacting_a_message_drops_its_content() {
+        let mut event: Event = serde_json::from_str(r#"{"type": "m.room.message", "event_id": "$m:fake.example.org", "sender": "@alice:fake.example.org",
+                                                         "origin_server_ts": 1, "unsigned": {"age": 5, "transaction_id": "t1"},
+                                                         "content": {"msgtype": "m.text", "body": "secret"}}"#).unwrap();
+
+        event.redact(&redaction_of("$m:fake.example.org"));
+
+        match event {
+            Event::RoomMessage(ref message) => {
+                assert_eq!(message.content, RoomMessageOptionType::Redacted(RedactedMessageContent {}));
+                assert_eq!(message.event_id().unwrap(), "$m:fake.example.org");
+                assert!(message.timestamp().is_some());
+
+                let unsigned = message.unsigned.as_ref().unwrap();
+                assert_eq!(unsigned.age, 5);
+                assert_eq!(unsigned.transaction_id, None);
+                assert_eq!(unsigned.redacted_by.as_ref().unwrap(), "$redaction:fake.example.org");
+                assert_eq!(unsigned.redacted_because.as_ref().unwrap().content.reason, Some(String::from("spam")));
+            },
+            ref other => panic!("expected a room message, got {:?}", other)
+        };
+    }
 
-        return Ok(send_event_response);
+    #[test]
+    fn redacting_state_keeps_what_the_spec_says() {
+        let mut member: Event = serde_json::from_str(r#"{"type": "m.room.member", "event_id": "$j:fake.example.org", "state_key": "@alice:fake.example.org",
+                                                          "content": {"membership": "join", "displayname": "Alice"}}"#).unwrap();
+        let mut topic: Event = serde_json::from_str(r#"{"type": "m.room.topic", "event_id": "$t:fake.example.org", "state_key": "",
+                                                         "content": {"topic": "rude words"}}"#).unwrap();
+
+        member.redact(&redaction_of("$j:fake.example.org"));
+        topic.redact(&redaction_of("$t:fake.example.org"));
+
+        match member {
+            Event::RoomMember(ref member) => {
+                assert_eq!(member.content.membership, "join");
+                assert_eq!(member.content.displayname, None);
+                assert_eq!(member.state_key(), Some("@alice:fake.example.org"));
+            },
+            ref other => panic!("expected a member event, got {:?}", other)
+        };
+
+        // A topic without its topic doesn't parse any more, but nothing of it is left either
+        match topic {
+            Event::Unknown(ref unknown) => {
+                assert_eq!(unknown.event_type, "m.room.topic");
+                assert_eq!(unknown.json["content"], serde_json::json!({}));
+                assert_eq!(topic.event_id(), Some("$t:fake.example.org"));
+            },
+            ref other => panic!("expected an unknown event, got {:?}", other)
+        };
+    }
+
+    #[test]
+    fn redactions_apply_to_earlier_events_in_a_batch() {
+        let mut events: Vec<Event> = serde_json::from_str(r#"[
+            {"type": "m.room.message", "event_id": "$a:fake.example.org", "content": {"msgtype": "m.text", "body": "keep"}},
+            {"type": "org.example.custom", "event_id": "$b:fake.example.org", "content": {"secret": true}},
+            {"type": "m.room.redaction", "event_id": "$r1:fake.example.org", "redacts": "$b:fake.example.org", "content": {}},
+            {"type": "m.room.redaction", "event_id": "$r2:fake.example.org", "redacts": "$c:fake.example.org", "content": {}},
+            {"type": "m.room.message", "event_id": "$c:fake.example.org", "content": {"msgtype": "m.text", "body": "after"}}
+        ]"#).unwrap();
+
+        apply_redactions(&mut events);
+
+        match events[0] {
+            Event::RoomMessage(ref message) => assert_eq!(message.content, RoomMessageOptionType::Message(text("keep"))),
+            ref other => panic!("expected a room message, got {:?}", other)
+        };
+        match events[1] {
+            Event::Unknown(ref unknown) => {
+                assert_eq!(unknown.json["content"], serde_json::json!({}));
+                assert_eq!(unknown.json["unsigned"]["redacted_by"], "$r1:fake.example.org");
+            },
+            ref other => panic!("expected an unknown event, got {:?}", other)
+        };
+
+        // Redactions only reach back; something later with the same ID is left alone
+        match events[4] {
+            Event::RoomMessage(ref message) => assert_eq!(message.content, RoomMessageOptionType::Message(text("after"))),
+            ref other => panic!("expected a room message, got {:?}", other)
+        };
     }
-}
 
+    #[test]
+    fn plain_messages_leave_out_the_format() {
+        assert_eq!(serde_json::to_value(&text("hi")).unwrap(), serde_json::json!({ "msgtype": "m.text", "body": "hi" }));
+    }
+}
@@ -0,0 +1,656 @@
+extern crate serde_json;
+extern crate reqwest;
+extern crate chrono;
+
+use std::cmp;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use matrix_client::*;
+use matrix_url::*;
+
+// Everything about talking to a homeserver which doesn't depend on how the request is actually
+// sent: building requests, deciding when to retry them and interpreting the responses. Both the
+// blocking MatrixClient and the AsyncMatrixClient are thin layers over this.
+
+pub const ROOM_MESSAGE_EVENT_TYPE: &'static str = "m.room.message";
+pub const REACTION_EVENT_TYPE: &'static str = "m.reaction";
+
+const TIMEOUT_DEFAULT_MS: u64 = 10000;
+const CONNECT_TIMEOUT_DEFAULT_MS: u64 = 10000;
+const READ_TIMEOUT_DEFAULT_MS: u64 = 30000;
+
+// Controls how requests are retried when the homeserver rate limits us or when a request fails
+// in a way that is likely to be transient (5xx responses, dropped connections)
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // How many times a request may be retried after the first attempt
+    pub max_retries: u32,
+
+    // The delay before the first retry of a transient failure; doubled on each attempt
+    pub initial_backoff: Duration,
+
+    // The most we'll ever wait between attempts when backing off on our own
+    pub max_backoff: Duration,
+
+    // If the server asks us to wait longer than this we give up and return the error instead
+    pub max_retry_after: Duration
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_retry_after: Duration::from_secs(60)
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn never() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            .. RetryPolicy::default()
+        }
+    }
+
+    // How long to back off after `attempt` failures in a row
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let multiplier = 1u32.checked_shl(attempt).unwrap_or(u32::max_value());
+        let backoff = self.initial_backoff.checked_mul(multiplier).unwrap_or(self.max_backoff);
+        cmp::min(backoff, self.max_backoff)
+    }
+
+    // How long to wait before retrying a request which got a response, or None if the response
+    // should be handed back to the caller as-is
+    pub fn delay_for_response(&self, response: &HttpResponse, idempotency: Idempotency, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_retries {
+            return None;
+        }
+
+        let matrix_error = if response.status.is_client_error() {
+            Some(MatrixError::from_response(response.status, &response.text()))
+        } else {
+            None
+        };
+
+        match matrix_error {
+            // A rate limited request was never acted on, so it's always safe to send it again
+            Some(ref matrix_error) if matrix_error.is_rate_limited() => {
+                return match matrix_error.retry_after() {
+                    Some(retry_after) => {
+                        if retry_after <= self.max_retry_after {
+                            Some(retry_after)
+                        } else {
+                            None
+                        }
+                    },
+                    None => Some(self.backoff(attempt))
+                };
+            },
+            _ => ()
+        };
+
+        if response.status.is_server_error() && idempotency == Idempotency::Idempotent {
+            return Some(self.backoff(attempt));
+        }
+
+        None
+    }
+
+    // How long to wait before retrying a request which failed without a response, or None if the
+    // error should be handed back to the caller
+    pub fn delay_for_error(&self, error: &MatrixClientError, idempotency: Idempotency, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_retries || idempotency != Idempotency::Idempotent {
+            return None;
+        }
+
+        let is_transient = match *error {
+            MatrixClientError::Http(ref e) => e.is_http() || e.get_ref().map(|e| e.is::<::std::io::Error>()).unwrap_or(false),
+            MatrixClientError::Io(_) => true,
+            _ => false
+        };
+
+        if is_transient {
+            Some(self.backoff(attempt))
+        } else {
+            None
+        }
+    }
+}
+
+// Whether sending a request more than once has the same effect as sending it once. Anything
+// which isn't is only ever retried when the server explicitly told us it didn't process it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Idempotency {
+    Idempotent,
+    NonIdempotent
+}
+
+// A fully read response from the homeserver
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: reqwest::StatusCode,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>
+}
+
+impl HttpResponse {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+// How long we're willing to wait on the homeserver. None means wait forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Timeouts {
+    // How long to wait for a connection to the homeserver to be established
+    pub connect: Option<Duration>,
+
+    // How long to wait for a response, and then for each read of its body
+    pub read: Option<Duration>,
+
+    // How long a whole call may take, including any retries
+    pub total: Option<Duration>
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: Some(Duration::from_millis(CONNECT_TIMEOUT_DEFAULT_MS)),
+            read: Some(Duration::from_millis(READ_TIMEOUT_DEFAULT_MS)),
+            total: None
+        }
+    }
+}
+
+impl Timeouts {
+    // The HTTP client can only enforce a single limit on each wait, so the tighter of the two wins
+    pub fn request_timeout(&self) -> Option<Duration> {
+        match (self.read, self.total) {
+            (Some(read), Some(total)) => Some(cmp::min(read, total)),
+            (read, None) => read,
+            (None, total) => total
+        }
+    }
+
+    // Raises the read and total timeouts so that a request which legitimately takes `duration`
+    // (e.g. a long-poll) won't be cut off
+    pub fn at_least(&self, duration: Duration) -> Timeouts {
+        Timeouts {
+            connect: self.connect,
+            read: self.read.map(|read| cmp::max(read, duration)),
+            total: self.total.map(|total| cmp::max(total, duration))
+        }
+    }
+}
+
+pub fn fits_before_deadline(deadline: Option<Instant>, delay: Duration) -> bool {
+    match deadline {
+        Some(deadline) => Instant::now() + delay < deadline,
+        None => true
+    }
+}
+
+// The timeouts for one attempt at a request, cut down so the attempt can't run past `deadline`.
+// None once the deadline has passed and there's no time left for another attempt.
+pub fn timeouts_for_attempt(timeouts: &Timeouts, deadline: Option<Instant>) -> Option<Timeouts> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => { return Some(*timeouts); }
+    };
+
+    let now = Instant::now();
+    if now >= deadline {
+        return None;
+    }
+
+    let remaining = deadline - now;
+
+    Some(Timeouts {
+        connect: Some(timeouts.connect.map_or(remaining, |connect| cmp::min(connect, remaining))),
+        read: timeouts.read,
+        total: Some(remaining)
+    })
+}
+
+// reqwest clients are built for one set of timeouts, and every attempt at a call with a total
+// timeout gets a different set, so only this many clients are kept around at once
+pub const HTTP_CLIENT_CACHE_SIZE: usize = 8;
+
+// Timeouts get their own error so callers can tell a slow server apart from a broken one
+pub fn from_reqwest_error(error: reqwest::Error) -> MatrixClientError {
+    if error.is_timeout() {
+        MatrixClientError::Timeout
+    } else {
+        MatrixClientError::Http(error)
+    }
+}
+
+pub fn content_type_of(headers: &reqwest::header::HeaderMap) -> Option<String> {
+    headers.get(reqwest::header::CONTENT_TYPE)
+           .and_then(|x| x.to_str().ok())
+           .map(String::from)
+}
+
+// Everything needed to send one request to the homeserver
+#[derive(Debug, Clone)]
+pub struct MatrixRequest {
+    pub method: reqwest::Method,
+    pub url: reqwest::Url,
+    pub access_token: Option<String>,
+    pub content_type: Option<String>,
+    pub body: Option<Vec<u8>>,
+    pub idempotency: Idempotency,
+
+    // Overrides the client's timeouts for just this request
+    pub timeouts: Option<Timeouts>
+}
+
+impl MatrixRequest {
+    pub fn new(method: reqwest::Method, url: reqwest::Url, idempotency: Idempotency) -> MatrixRequest {
+        MatrixRequest {
+            method: method,
+            url: url,
+            access_token: None,
+            content_type: None,
+            body: None,
+            idempotency: idempotency,
+            timeouts: None
+        }
+    }
+
+    pub fn access_token(mut self, access_token: String) -> MatrixRequest {
+        self.access_token = Some(access_token);
+        self
+    }
+
+    pub fn body(mut self, content_type: &str, body: Vec<u8>) -> MatrixRequest {
+        self.content_type = Some(String::from(content_type));
+        self.body = Some(body);
+        self
+    }
+
+    pub fn json<T: Serialize + ?Sized>(self, body: &T) -> Result<MatrixRequest, MatrixClientError> {
+        let body = serde_json::to_vec(body).map_err(MatrixClientError::Json)?;
+        Ok(self.body("application/json", body))
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> MatrixRequest {
+        self.timeouts = Some(timeouts);
+        self
+    }
+}
+
+pub fn check_response(response: &HttpResponse) -> Result<(), MatrixClientError> {
+    if reqwest::StatusCode::OK != response.status {
+        return Err(MatrixClientError::Matrix(MatrixError::from_response(response.status, &response.text())));
+    }
+
+    Ok(())
+}
+
+pub fn parse_json_response<T: DeserializeOwned>(response: &HttpResponse) -> Result<T, MatrixClientError> {
+    check_response(response)?;
+
+    let parsed: T = serde_json::from_slice(&response.body).map_err(MatrixClientError::Json)?;
+
+    Ok(parsed)
+}
+
+pub fn parse_media_response(response: HttpResponse) -> Result<MediaResponse, MatrixClientError> {
+    check_response(&response)?;
+
+    Ok(MediaResponse {
+        content_type: response.content_type,
+        data: response.body
+    })
+}
+
+// Transaction IDs only need to be unique per access token, so combining the time the generator
+// was created with a counter is enough to keep them from colliding, even across restarts
+#[derive(Debug)]
+pub struct TransactionIds {
+    prefix: String,
+    counter: AtomicUsize
+}
+
+impl TransactionIds {
+    pub fn new() -> TransactionIds {
+        TransactionIds {
+            prefix: format!("{}", Utc::now().timestamp_millis()),
+            counter: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn next(&self) -> String {
+        let count = self.counter.fetch_add(1, Ordering::SeqCst);
+        format!("{}.{}", self.prefix, count)
+    }
+}
+
+impl Default for TransactionIds {
+    fn default() -> TransactionIds {
+        TransactionIds::new()
+    }
+}
+
+// The login state for one account on one homeserver, and the requests which can be made with it
+#[derive(Debug, Clone, Default)]
+pub struct ClientCore {
+    homeserver: String,
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+    local_device_id: Option<String>,
+    server_device_id: Option<String>,
+    user_id: Option<UserId>
+}
+
+impl ClientCore {
+    pub fn new(homeserver: &str, device_id: Option<String>) -> ClientCore {
+        ClientCore {
+            homeserver: String::from(homeserver),
+            access_token: None,
+            refresh_token: None,
+            local_device_id: device_id,
+            server_device_id: None,
+            user_id: None
+        }
+    }
+
+    pub fn apply_login(&mut self, login_response: &LoginResponse) -> () {
+        self.access_token = Some(login_response.access_token.clone());
+        self.refresh_token = login_response.refresh_token.clone();
+        self.server_device_id = login_response.device_id.clone();
+        self.user_id = Some(login_response.user_id.clone());
+    }
+
+    pub fn apply_logout(&mut self) -> () {
+        self.access_token = None;
+        self.refresh_token = None;
+        self.server_device_id = None;
+    }
+
+    fn client_url(&self, segments: &[&str]) -> Result<reqwest::Url, MatrixClientError> {
+        build_url(self.homeserver.as_str(), &CLIENT_API_PREFIX, segments).map_err(MatrixClientError::UrlError)
+    }
+
+    fn media_url(&self, segments: &[&str]) -> Result<reqwest::Url, MatrixClientError> {
+        build_url(self.homeserver.as_str(), &MEDIA_API_PREFIX, segments).map_err(MatrixClientError::UrlError)
+    }
+
+    fn get_access_token(&self) -> Result<String, MatrixClientError> {
+        let access_token = match self.access_token {
+            Some(ref x) => x.clone(),
+            None => { return Err(MatrixClientError::NotLoggedIn); }
+        };
+
+        Ok(access_token)
+    }
+
+    fn get_user_id(&self) -> Result<UserId, MatrixClientError> {
+        let user_id = match self.user_id {
+            Some(ref x) => x.clone(),
+            None => { return Err(MatrixClientError::NotLoggedIn); }
+        };
+
+        Ok(user_id)
+    }
+
+    // An authenticated request to the client-server API
+    fn client_request(&self, method: reqwest::Method, segments: &[&str], idempotency: Idempotency) -> Result<MatrixRequest, MatrixClientError> {
+        let request_url = self.client_url(segments)?;
+        let access_token = self.get_access_token()?;
+
+        Ok(MatrixRequest::new(method, request_url, idempotency).access_token(access_token))
+    }
+
+    pub fn get_supported_versions_request(&self) -> Result<MatrixRequest, MatrixClientError> {
+        let request_url = build_url(self.homeserver.as_str(), &["_matrix", "client"], &["versions"]).map_err(MatrixClientError::UrlError)?;
+
+        Ok(MatrixRequest::new(reqwest::Method::GET, request_url, Idempotency::Idempotent))
+    }
+
+    pub fn login_request(&self, user: &str, password: &str) -> Result<MatrixRequest, MatrixClientError> {
+        let request_url = self.client_url(&["login"])?;
+
+        let login_request = LoginRequest {
+            password: String::from(password),
+            login_type: String::from("m.login.password"),
+            user: Some(String::from(user)),
+            medium: None,
+            address: None,
+            device_id: self.local_device_id.clone()
+        };
+
+        MatrixRequest::new(reqwest::Method::POST, request_url, Idempotency::NonIdempotent).json(&login_request)
+    }
+
+    pub fn logout_request(&self) -> Result<MatrixRequest, MatrixClientError> {
+        self.client_request(reqwest::Method::POST, &["logout"], Idempotency::NonIdempotent)
+    }
+
+    pub fn logout_all_request(&self) -> Result<MatrixRequest, MatrixClientError> {
+        self.client_request(reqwest::Method::POST, &["logout", "all"], Idempotency::NonIdempotent)
+    }
+
+    pub fn list_public_rooms_request(&self) -> Result<MatrixRequest, MatrixClientError> {
+        self.client_request(reqwest::Method::GET, &["publicRooms"], Idempotency::Idempotent)
+    }
+
+    pub fn join_room_request(&self, room_id_or_alias: &RoomIdOrAliasId) -> Result<MatrixRequest, MatrixClientError> {
+        self.client_request(reqwest::Method::POST, &["join", room_id_or_alias.as_str()], Idempotency::Idempotent)
+    }
+
+    pub fn create_filter_request(&self, filter: &Filter) -> Result<MatrixRequest, MatrixClientError> {
+        let user_id = self.get_user_id()?;
+
+        self.client_request(reqwest::Method::POST, &["user", user_id.as_str(), "filter"], Idempotency::NonIdempotent)?.json(filter)
+    }
+
+    pub fn get_filter_request(&self, filter_id: &str) -> Result<MatrixRequest, MatrixClientError> {
+        let user_id = self.get_user_id()?;
+
+        self.client_request(reqwest::Method::GET, &["user", user_id.as_str(), "filter", filter_id], Idempotency::Idempotent)
+    }
+
+    pub fn create_room_request(&self, create_room_request: &CreateRoomRequest) -> Result<MatrixRequest, MatrixClientError> {
+        self.client_request(reqwest::Method::POST, &["createRoom"], Idempotency::NonIdempotent)?.json(create_room_request)
+    }
+
+    pub fn room_membership_request<T: Serialize>(&self, room: &RoomId, action: &str, request: &T) -> Result<MatrixRequest, MatrixClientError> {
+        self.client_request(reqwest::Method::POST, &["rooms", room.as_str(), action], Idempotency::NonIdempotent)?.json(request)
+    }
+
+    pub fn room_membership_for_user_request(&self, room: &RoomId, action: &str, user_id: &UserId, reason: Option<&str>) -> Result<MatrixRequest, MatrixClientError> {
+        let membership_request = RoomMembershipRequest {
+            user_id: user_id.clone(),
+            reason: reason.map(String::from)
+        };
+
+        self.room_membership_request(room, action, &membership_request)
+    }
+
+    pub fn sync_request(&self, filter: Option<&SyncFilter>, since: Option<&String>, full_state: Option<bool>, timeout_ms: Option<u64>, timeouts: Timeouts) -> Result<MatrixRequest, MatrixClientError> {
+        let mut request = self.client_request(reqwest::Method::GET, &["sync"], Idempotency::Idempotent)?;
+
+        match filter {
+            None => (),
+            Some(&SyncFilter::FilterId(ref filter_id)) => {
+                request.url.query_pairs_mut().append_pair("filter", filter_id.as_str());
+            },
+            Some(&SyncFilter::Inline(ref filter)) => {
+                let filter_string = serde_json::to_string(filter).map_err(MatrixClientError::Json)?;
+                request.url.query_pairs_mut().append_pair("filter", filter_string.as_str());
+            }
+        };
+
+        match since {
+            None => (),
+            Some(since_string) => {
+                request.url.query_pairs_mut().append_pair("since", since_string);
+            }
+        };
+
+        match full_state {
+            None => (),
+            Some(full_state_string) => {
+                request.url.query_pairs_mut().append_pair("full_state", full_state_string.to_string().as_str());
+            }
+        };
+
+        // The server may legitimately hold a long-poll open for the whole timeout, so the
+        // request itself has to be allowed that long plus some slack
+        let timeouts = match timeout_ms {
+            None => timeouts,
+            Some(timeout_ms) => {
+                request.url.query_pairs_mut().append_pair("timeout", format!("{}", timeout_ms).as_str());
+                timeouts.at_least(Duration::from_millis(timeout_ms + TIMEOUT_DEFAULT_MS))
+            }
+        };
+
+        Ok(request.timeouts(timeouts))
+    }
+
+    pub fn room_messages_request(&self, room: &RoomId, from: &str, to: Option<&str>, dir: Direction, limit: Option<u64>, filter: Option<&RoomEventFilter>) -> Result<MatrixRequest, MatrixClientError> {
+        let mut request = self.client_request(reqwest::Method::GET, &["rooms", room.as_str(), "messages"], Idempotency::Idempotent)?;
+
+        request.url.query_pairs_mut().append_pair("from", from);
+        request.url.query_pairs_mut().append_pair("dir", dir.as_str());
+
+        match to {
+            None => (),
+            Some(to_string) => {
+                request.url.query_pairs_mut().append_pair("to", to_string);
+            }
+        };
+
+        match limit {
+            None => (),
+            Some(limit) => {
+                request.url.query_pairs_mut().append_pair("limit", format!("{}", limit).as_str());
+            }
+        };
+
+        match filter {
+            None => (),
+            Some(filter) => {
+                let filter_string = serde_json::to_string(filter).map_err(MatrixClientError::Json)?;
+                request.url.query_pairs_mut().append_pair("filter", filter_string.as_str());
+            }
+        };
+
+        Ok(request)
+    }
+
+    fn room_state_request(&self, method: reqwest::Method, room: &RoomId, event_type: Option<&str>, state_key: Option<&str>) -> Result<MatrixRequest, MatrixClientError> {
+        let mut segments = vec!["rooms", room.as_str(), "state"];
+
+        match event_type {
+            None => (),
+            Some(event_type) => {
+                segments.push(event_type);
+            }
+        };
+
+        // An empty state key is addressed by leaving the final path segment off entirely
+        match state_key {
+            None | Some("") => (),
+            Some(state_key) => {
+                segments.push(state_key);
+            }
+        };
+
+        self.client_request(method, &segments, Idempotency::Idempotent)
+    }
+
+    pub fn send_state_event_request<T: Serialize>(&self, room: &RoomId, event_type: &str, state_key: &str, content: &T) -> Result<MatrixRequest, MatrixClientError> {
+        self.room_state_request(reqwest::Method::PUT, room, Some(event_type), Some(state_key))?.json(content)
+    }
+
+    pub fn get_state_event_request(&self, room: &RoomId, event_type: &str, state_key: &str) -> Result<MatrixRequest, MatrixClientError> {
+        self.room_state_request(reqwest::Method::GET, room, Some(event_type), Some(state_key))
+    }
+
+    pub fn get_room_state_request(&self, room: &RoomId) -> Result<MatrixRequest, MatrixClientError> {
+        self.room_state_request(reqwest::Method::GET, room, None, None)
+    }
+
+    pub fn upload_request(&self, data: Vec<u8>, content_type: &str, filename: Option<&str>) -> Result<MatrixRequest, MatrixClientError> {
+        let mut request_url = self.media_url(&["upload"])?;
+
+        match filename {
+            None => (),
+            Some(filename) => {
+                request_url.query_pairs_mut().append_pair("filename", filename);
+            }
+        };
+
+        let access_token = self.get_access_token()?;
+
+        Ok(MatrixRequest::new(reqwest::Method::POST, request_url, Idempotency::NonIdempotent)
+                .access_token(access_token)
+                .body(content_type, data))
+    }
+
+    pub fn download_request(&self, mxc: &MxcUri) -> Result<MatrixRequest, MatrixClientError> {
+        let request_url = self.media_url(&["download", mxc.server_name(), mxc.media_id()])?;
+        let access_token = self.get_access_token()?;
+
+        Ok(MatrixRequest::new(reqwest::Method::GET, request_url, Idempotency::Idempotent).access_token(access_token))
+    }
+
+    pub fn thumbnail_request(&self, mxc: &MxcUri, width: u64, height: u64, method: ThumbnailMethod) -> Result<MatrixRequest, MatrixClientError> {
+        let mut request_url = self.media_url(&["thumbnail", mxc.server_name(), mxc.media_id()])?;
+
+        request_url.query_pairs_mut().append_pair("width", format!("{}", width).as_str());
+        request_url.query_pairs_mut().append_pair("height", format!("{}", height).as_str());
+        request_url.query_pairs_mut().append_pair("method", method.as_str());
+
+        let access_token = self.get_access_token()?;
+
+        Ok(MatrixRequest::new(reqwest::Method::GET, request_url, Idempotency::Idempotent).access_token(access_token))
+    }
+
+    // Sending again with the same transaction ID will not create a second event, which is what
+    // makes this safe to retry
+    pub fn send_room_event_request<T: Serialize>(&self, room: &RoomId, event_type: &str, content: &T, txn_id: &str) -> Result<MatrixRequest, MatrixClientError> {
+        self.client_request(reqwest::Method::PUT, &["rooms", room.as_str(), "send", event_type, txn_id], Idempotency::Idempotent)?.json(content)
+    }
+
+    pub fn redact_request(&self, room: &RoomId, event_id: &EventId, reason: Option<&str>, txn_id: &str) -> Result<MatrixRequest, MatrixClientError> {
+        let content = RoomRedactionEvent {
+            reason: reason.map(String::from)
+        };
+
+        self.client_request(reqwest::Method::PUT, &["rooms", room.as_str(), "redact", event_id.as_str(), txn_id], Idempotency::Idempotent)?.json(&content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_urls_leave_an_empty_key_off_the_path() {
+        let mut core = ClientCore::new("https://example.org", None);
+        core.access_token = Some(String::from("token"));
+        let room = RoomId::parse("!room:example.org").unwrap();
+
+        let url = core.room_state_request(reqwest::Method::GET, &room, Some("m.room.topic"), Some("")).unwrap().url;
+        assert_eq!(url.path(), "/_matrix/client/r0/rooms/!room:example.org/state/m.room.topic");
+
+        let url = core.room_state_request(reqwest::Method::GET, &room, Some("m.room.member"), Some("@alice:example.org")).unwrap().url;
+        assert_eq!(url.path(), "/_matrix/client/r0/rooms/!room:example.org/state/m.room.member/@alice:example.org");
+
+        let url = core.room_state_request(reqwest::Method::GET, &room, None, None).unwrap().url;
+        assert_eq!(url.path(), "/_matrix/client/r0/rooms/!room:example.org/state");
+    }
+}
@@ -0,0 +1,239 @@
+extern crate serde_json;
+extern crate reqwest;
+
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorCode {
+    Forbidden,
+    UnknownToken,
+    MissingToken,
+    BadJson,
+    NotJson,
+    NotFound,
+    LimitExceeded,
+    Unknown,
+    Unrecognized,
+    Unauthorized,
+    UserDeactivated,
+    UserInUse,
+    InvalidUsername,
+    RoomInUse,
+    InvalidRoomState,
+    ThreepidInUse,
+    ThreepidNotFound,
+    ThreepidAuthFailed,
+    ThreepidDenied,
+    ServerNotTrusted,
+    UnsupportedRoomVersion,
+    IncompatibleRoomVersion,
+    BadState,
+    GuestAccessForbidden,
+    CaptchaNeeded,
+    CaptchaInvalid,
+    MissingParam,
+    InvalidParam,
+    TooLarge,
+    Exclusive,
+    ResourceLimitExceeded,
+    CannotLeaveServerNoticeRoom,
+
+    // Anything this version of jacobian doesn't know about, kept verbatim
+    Other(String)
+}
+
+impl ErrorCode {
+    pub fn from_errcode(errcode: &str) -> ErrorCode {
+        match errcode {
+            "M_FORBIDDEN" => ErrorCode::Forbidden,
+            "M_UNKNOWN_TOKEN" => ErrorCode::UnknownToken,
+            "M_MISSING_TOKEN" => ErrorCode::MissingToken,
+            "M_BAD_JSON" => ErrorCode::BadJson,
+            "M_NOT_JSON" => ErrorCode::NotJson,
+            "M_NOT_FOUND" => ErrorCode::NotFound,
+            "M_LIMIT_EXCEEDED" => ErrorCode::LimitExceeded,
+            "M_UNKNOWN" => ErrorCode::Unknown,
+            "M_UNRECOGNIZED" => ErrorCode::Unrecognized,
+            "M_UNAUTHORIZED" => ErrorCode::Unauthorized,
+            "M_USER_DEACTIVATED" => ErrorCode::UserDeactivated,
+            "M_USER_IN_USE" => ErrorCode::UserInUse,
+            "M_INVALID_USERNAME" => ErrorCode::InvalidUsername,
+            "M_ROOM_IN_USE" => ErrorCode::RoomInUse,
+            "M_INVALID_ROOM_STATE" => ErrorCode::InvalidRoomState,
+            "M_THREEPID_IN_USE" => ErrorCode::ThreepidInUse,
+            "M_THREEPID_NOT_FOUND" => ErrorCode::ThreepidNotFound,
+            "M_THREEPID_AUTH_FAILED" => ErrorCode::ThreepidAuthFailed,
+            "M_THREEPID_DENIED" => ErrorCode::ThreepidDenied,
+            "M_SERVER_NOT_TRUSTED" => ErrorCode::ServerNotTrusted,
+            "M_UNSUPPORTED_ROOM_VERSION" => ErrorCode::UnsupportedRoomVersion,
+            "M_INCOMPATIBLE_ROOM_VERSION" => ErrorCode::IncompatibleRoomVersion,
+            "M_BAD_STATE" => ErrorCode::BadState,
+            "M_GUEST_ACCESS_FORBIDDEN" => ErrorCode::GuestAccessForbidden,
+            "M_CAPTCHA_NEEDED" => ErrorCode::CaptchaNeeded,
+            "M_CAPTCHA_INVALID" => ErrorCode::CaptchaInvalid,
+            "M_MISSING_PARAM" => ErrorCode::MissingParam,
+            "M_INVALID_PARAM" => ErrorCode::InvalidParam,
+            "M_TOO_LARGE" => ErrorCode::TooLarge,
+            "M_EXCLUSIVE" => ErrorCode::Exclusive,
+            "M_RESOURCE_LIMIT_EXCEEDED" => ErrorCode::ResourceLimitExceeded,
+            "M_CANNOT_LEAVE_SERVER_NOTICE_ROOM" => ErrorCode::CannotLeaveServerNoticeRoom,
+            other => ErrorCode::Other(String::from(other))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match *self {
+            ErrorCode::Forbidden => "M_FORBIDDEN",
+            ErrorCode::UnknownToken => "M_UNKNOWN_TOKEN",
+            ErrorCode::MissingToken => "M_MISSING_TOKEN",
+            ErrorCode::BadJson => "M_BAD_JSON",
+            ErrorCode::NotJson => "M_NOT_JSON",
+            ErrorCode::NotFound => "M_NOT_FOUND",
+            ErrorCode::LimitExceeded => "M_LIMIT_EXCEEDED",
+            ErrorCode::Unknown => "M_UNKNOWN",
+            ErrorCode::Unrecognized => "M_UNRECOGNIZED",
+            ErrorCode::Unauthorized => "M_UNAUTHORIZED",
+            ErrorCode::UserDeactivated => "M_USER_DEACTIVATED",
+            ErrorCode::UserInUse => "M_USER_IN_USE",
+            ErrorCode::InvalidUsername => "M_INVALID_USERNAME",
+            ErrorCode::RoomInUse => "M_ROOM_IN_USE",
+            ErrorCode::InvalidRoomState => "M_INVALID_ROOM_STATE",
+            ErrorCode::ThreepidInUse => "M_THREEPID_IN_USE",
+            ErrorCode::ThreepidNotFound => "M_THREEPID_NOT_FOUND",
+            ErrorCode::ThreepidAuthFailed => "M_THREEPID_AUTH_FAILED",
+            ErrorCode::ThreepidDenied => "M_THREEPID_DENIED",
+            ErrorCode::ServerNotTrusted => "M_SERVER_NOT_TRUSTED",
+            ErrorCode::UnsupportedRoomVersion => "M_UNSUPPORTED_ROOM_VERSION",
+            ErrorCode::IncompatibleRoomVersion => "M_INCOMPATIBLE_ROOM_VERSION",
+            ErrorCode::BadState => "M_BAD_STATE",
+            ErrorCode::GuestAccessForbidden => "M_GUEST_ACCESS_FORBIDDEN",
+            ErrorCode::CaptchaNeeded => "M_CAPTCHA_NEEDED",
+            ErrorCode::CaptchaInvalid => "M_CAPTCHA_INVALID",
+            ErrorCode::MissingParam => "M_MISSING_PARAM",
+            ErrorCode::InvalidParam => "M_INVALID_PARAM",
+            ErrorCode::TooLarge => "M_TOO_LARGE",
+            ErrorCode::Exclusive => "M_EXCLUSIVE",
+            ErrorCode::ResourceLimitExceeded => "M_RESOURCE_LIMIT_EXCEEDED",
+            ErrorCode::CannotLeaveServerNoticeRoom => "M_CANNOT_LEAVE_SERVER_NOTICE_ROOM",
+            ErrorCode::Other(ref errcode) => errcode.as_str()
+        }
+    }
+}
+
+// The standard error body returned by the homeserver on any non-200 response
+#[derive(Deserialize, Debug, Default)]
+struct ErrorResponse {
+    errcode: String,
+    error: Option<String>,
+    retry_after_ms: Option<u64>,
+    soft_logout: Option<bool>
+}
+
+#[derive(Debug)]
+pub struct MatrixError {
+    pub status: reqwest::StatusCode,
+
+    // None when the body wasn't a standard Matrix error (e.g. an HTML page from a proxy)
+    pub errcode: Option<ErrorCode>,
+
+    pub error: Option<String>,
+    pub retry_after_ms: Option<u64>,
+    pub soft_logout: Option<bool>,
+
+    // The unparsed response body, kept around for logging
+    pub body: String
+}
+
+impl MatrixError {
+    pub fn from_response(status: reqwest::StatusCode, body: &str) -> MatrixError {
+        match serde_json::from_str::<ErrorResponse>(body) {
+            Ok(error_response) => MatrixError {
+                status: status,
+                errcode: Some(ErrorCode::from_errcode(error_response.errcode.as_str())),
+                error: error_response.error,
+                retry_after_ms: error_response.retry_after_ms,
+                soft_logout: error_response.soft_logout,
+                body: String::from(body)
+            },
+            Err(_) => MatrixError {
+                status: status,
+                errcode: None,
+                error: None,
+                retry_after_ms: None,
+                soft_logout: None,
+                body: String::from(body)
+            }
+        }
+    }
+
+    pub fn is(&self, errcode: ErrorCode) -> bool {
+        self.errcode == Some(errcode)
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        self.status == reqwest::StatusCode::TOO_MANY_REQUESTS || self.is(ErrorCode::LimitExceeded)
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after_ms.map(Duration::from_millis)
+    }
+}
+
+impl fmt::Display for MatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.errcode, &self.error) {
+            (&Some(ref errcode), &Some(ref error)) => write!(f, "{} ({}): {}", errcode.as_str(), self.status, error),
+            (&Some(ref errcode), &None) => write!(f, "{} ({})", errcode.as_str(), self.status),
+            (&None, _) => write!(f, "Got error response from the server: {}; Contents: {}", self.status, self.body)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_json_bodies_are_kept_for_logging() {
+        let error = MatrixError::from_response(reqwest::StatusCode::BAD_GATEWAY, "<html>Bad Gateway</html>");
+
+        assert_eq!(error.errcode, None);
+        assert_eq!(error.error, None);
+        assert_eq!(error.body, "<html>Bad Gateway</html>");
+        assert_eq!(error.to_string(), "Got error response from the server: 502 Bad Gateway; Contents: <html>Bad Gateway</html>");
+    }
+
+    #[test]
+    fn errcodes_round_trip() {
+        assert_eq!(ErrorCode::from_errcode("M_FORBIDDEN"), ErrorCode::Forbidden);
+        assert_eq!(ErrorCode::Forbidden.as_str(), "M_FORBIDDEN");
+
+        let other = ErrorCode::from_errcode("ORG_EXAMPLE_CUSTOM");
+        assert_eq!(other, ErrorCode::Other(String::from("ORG_EXAMPLE_CUSTOM")));
+        assert_eq!(other.as_str(), "ORG_EXAMPLE_CUSTOM");
+    }
+
+    #[test]
+    fn rate_limits_say_how_long_to_wait() {
+        let body = r#"{"errcode": "M_LIMIT_EXCEEDED", "error": "Too many requests", "retry_after_ms": 2000}"#;
+        let error = MatrixError::from_response(reqwest::StatusCode::TOO_MANY_REQUESTS, body);
+
+        assert!(error.is_rate_limited());
+        assert_eq!(error.retry_after(), Some(Duration::from_millis(2000)));
+
+        let error = MatrixError::from_response(reqwest::StatusCode::FORBIDDEN, r#"{"errcode": "M_FORBIDDEN"}"#);
+        assert!(!error.is_rate_limited());
+        assert_eq!(error.retry_after(), None);
+    }
+
+    #[test]
+    fn errors_display_their_errcode_and_status() {
+        let body = r#"{"errcode": "M_FORBIDDEN", "error": "You are not invited to this room."}"#;
+        let error = MatrixError::from_response(reqwest::StatusCode::FORBIDDEN, body);
+        assert_eq!(error.to_string(), "M_FORBIDDEN (403 Forbidden): You are not invited to this room.");
+
+        let error = MatrixError::from_response(reqwest::StatusCode::NOT_FOUND, r#"{"errcode": "M_NOT_FOUND"}"#);
+        assert_eq!(error.to_string(), "M_NOT_FOUND (404 Not Found)");
+    }
+}
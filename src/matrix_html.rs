@@ -0,0 +1,735 @@
+// HTML handling for the org.matrix.custom.html message format: a sanitizer which keeps only the
+// tags and attributes the spec allows, and a plain text rendering for use as a message's body.
+// Neither needs a full HTML parser; a forgiving tokenizer is enough for what clients send.
+// Outgoing messages can also be written in CommonMark and rendered down to the same subset.
+
+use pulldown_cmark::{html, Options, Parser};
+
+pub const HTML_FORMAT: &'static str = "org.matrix.custom.html";
+
+// Deeper nesting than this is dropped, as the spec recommends
+const MAX_NESTING_DEPTH: usize = 100;
+
+const ALLOWED_TAGS: [&'static str; 39] = [
+    "font", "del", "h1", "h2", "h3", "h4", "h5", "h6", "blockquote", "p", "a", "ul", "ol", "sup",
+    "sub", "li", "b", "i", "u", "strong", "em", "strike", "s", "code", "hr", "br", "div", "table",
+    "thead", "tbody", "tr", "th", "td", "caption", "pre", "span", "img", "details", "summary"
+];
+
+// Allowed tags which never have contents or a closing tag
+const VOID_TAGS: [&'static str; 3] = ["br", "hr", "img"];
+
+// Disallowed tags whose contents have to go too, not just the tags themselves
+const DROPPED_WITH_CONTENTS: [&'static str; 14] = [
+    "script", "style", "head", "title", "iframe", "object", "embed", "noscript", "textarea",
+    "select", "template", "svg", "math", "mx-reply"
+];
+
+const ALLOWED_LINK_SCHEMES: [&'static str; 5] = ["http", "https", "ftp", "mailto", "magnet"];
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Text(&'a str),
+
+    StartTag {
+        name: String,
+        attributes: Vec<(String, String)>,
+        self_closing: bool
+    },
+
+    EndTag(String)
+}
+
+fn is_tag_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-'
+}
+
+// Splits HTML into text and tags. Comments, doctypes and processing instructions are dropped and
+// anything which doesn't look like a tag is treated as text.
+fn tokenize<'a>(html: &'a str) -> Vec<Token<'a>> {
+    let mut tokens = Vec::new();
+    let mut text_start = 0;
+    let mut pos = 0;
+
+    while pos < html.len() {
+        let rest = &html[pos..];
+
+        if !rest.starts_with('<') {
+            pos += rest.chars().next().map(char::len_utf8).unwrap_or(1);
+            continue;
+        }
+
+        let (token, consumed) = if rest.starts_with("<!--") {
+            (None, rest.find("-->").map(|end| end + 3).unwrap_or(rest.len()))
+        } else if rest.starts_with("<!") || rest.starts_with("<?") {
+            (None, rest.find('>').map(|end| end + 1).unwrap_or(rest.len()))
+        } else if rest.starts_with("</") && rest[2..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let name_len = rest[2..].find(|c: char| !is_tag_name_char(c)).unwrap_or(rest.len() - 2);
+            let name = rest[2..2 + name_len].to_ascii_lowercase();
+            (Some(Token::EndTag(name)), rest.find('>').map(|end| end + 1).unwrap_or(rest.len()))
+        } else if rest[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let (token, consumed) = parse_start_tag(rest);
+            (Some(token), consumed)
+        } else {
+            // A lone '<' is just text
+            pos += 1;
+            continue;
+        };
+
+        if text_start < pos {
+            tokens.push(Token::Text(&html[text_start..pos]));
+        }
+
+        match token {
+            Some(token) => tokens.push(token),
+            None => ()
+        };
+
+        pos += consumed;
+        text_start = pos;
+    }
+
+    if text_start < html.len() {
+        tokens.push(Token::Text(&html[text_start..]));
+    }
+
+    tokens
+}
+
+// Parses a start tag at the beginning of `s`, returning it and how many bytes it took up
+fn parse_start_tag<'a>(s: &'a str) -> (Token<'a>, usize) {
+    let name_len = s[1..].find(|c: char| !is_tag_name_char(c)).unwrap_or(s.len() - 1);
+    let name = s[1..1 + name_len].to_ascii_lowercase();
+
+    let mut attributes = Vec::new();
+    let mut self_closing = false;
+    let mut pos = 1 + name_len;
+
+    loop {
+        // Malformed tags can run off the end of the input; that's just the end of the tag
+        pos = pos.min(s.len());
+
+        let rest = &s[pos..];
+        let trimmed = rest.trim_start();
+        pos += rest.len() - trimmed.len();
+
+        if trimmed.is_empty() {
+            break;
+        }
+
+        if trimmed.starts_with('>') {
+            pos += 1;
+            break;
+        }
+
+        if trimmed.starts_with("/>") {
+            self_closing = true;
+            pos += 2;
+            break;
+        }
+
+        if trimmed.starts_with('/') {
+            pos += 1;
+            continue;
+        }
+
+        let attribute_len = trimmed.find(|c: char| c.is_whitespace() || c == '=' || c == '>' || c == '/').unwrap_or(trimmed.len()).max(1);
+        let attribute_name = trimmed[..attribute_len].to_ascii_lowercase();
+        pos += attribute_len;
+
+        let rest = &s[pos..];
+        let after_name = rest.trim_start();
+        if !after_name.starts_with('=') {
+            attributes.push((attribute_name, String::new()));
+            continue;
+        }
+
+        pos += rest.len() - after_name.len() + 1;
+        let rest = &s[pos..];
+        let value_start = rest.trim_start();
+        pos += rest.len() - value_start.len();
+
+        let value = match value_start.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => {
+                let value = &value_start[1..];
+                match value.find(quote) {
+                    Some(value_len) => {
+                        pos += value_len + 2;
+                        &value[..value_len]
+                    },
+                    None => {
+                        // No closing quote, so the value runs to the end of the input
+                        pos += value.len() + 1;
+                        value
+                    }
+                }
+            },
+            _ => {
+                let value_len = value_start.find(|c: char| c.is_whitespace() || c == '>').unwrap_or(value_start.len());
+                pos += value_len;
+                &value_start[..value_len]
+            }
+        };
+
+        attributes.push((attribute_name, decode_entities(value)));
+    }
+
+    let token = Token::StartTag {
+        name: name,
+        attributes: attributes,
+        self_closing: self_closing
+    };
+
+    (token, pos.min(s.len()))
+}
+
+// Decodes character references. Named references beyond the common few are left as they are.
+pub fn decode_entities(s: &str) -> String {
+    let mut decoded = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        let entity = match rest.find(';') {
+            Some(semicolon) if semicolon <= 10 => &rest[1..semicolon],
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                u32::from_str_radix(&entity[2..], 16).ok().and_then(::std::char::from_u32)
+            },
+            _ if entity.starts_with('#') => {
+                entity[1..].parse::<u32>().ok().and_then(::std::char::from_u32)
+            },
+            _ => None
+        };
+
+        match c {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[entity.len() + 2..];
+            },
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        };
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+fn is_allowed_link(href: &str) -> bool {
+    match href.find(':') {
+        Some(colon) => {
+            let scheme = href[..colon].trim().to_ascii_lowercase();
+            ALLOWED_LINK_SCHEMES.contains(&scheme.as_str())
+        },
+        None => false
+    }
+}
+
+fn is_allowed_attribute(tag: &str, attribute: &str, value: &str) -> bool {
+    match (tag, attribute) {
+        ("font", "color") | ("font", "data-mx-bg-color") | ("font", "data-mx-color") => true,
+        ("span", "data-mx-bg-color") | ("span", "data-mx-color") | ("span", "data-mx-spoiler") => true,
+        ("a", "name") | ("a", "target") => true,
+        ("a", "href") => is_allowed_link(value),
+        ("img", "width") | ("img", "height") | ("img", "alt") | ("img", "title") => true,
+
+        // Clients must never fetch images from anywhere but the homeserver's media repository
+        ("img", "src") => value.starts_with("mxc://"),
+
+        ("ol", "start") => value.parse::<i64>().is_ok(),
+        ("code", "class") => value.starts_with("language-"),
+        _ => false
+    }
+}
+
+// Drops every tag and attribute the spec doesn't allow in formatted_body, keeping the text
+// inside them (except for things like <script>), and closes any tags left open
+pub fn sanitize_html(html: &str) -> String {
+    let mut sanitized = String::with_capacity(html.len());
+    let mut open_tags: Vec<String> = Vec::new();
+
+    // The dropped tag we're inside of and how deeply it's nested in itself
+    let mut dropping: Option<(String, usize)> = None;
+
+    for token in tokenize(html) {
+        if let Some((ref dropped_name, ref mut depth)) = dropping {
+            match token {
+                Token::StartTag { ref name, self_closing, .. } if name == dropped_name && !self_closing => { *depth += 1; },
+                Token::EndTag(ref name) if name == dropped_name => { *depth -= 1; },
+                _ => ()
+            };
+
+            if *depth > 0 {
+                continue;
+            }
+        }
+
+        if dropping.as_ref().map(|&(_, depth)| depth == 0).unwrap_or(false) {
+            dropping = None;
+            continue;
+        }
+
+        match token {
+            Token::Text(text) => {
+                sanitized.push_str(escape_html(decode_entities(text).as_str()).as_str());
+            },
+            Token::StartTag { name, attributes, self_closing } => {
+                if DROPPED_WITH_CONTENTS.contains(&name.as_str()) {
+                    if !self_closing {
+                        dropping = Some((name, 1));
+                    }
+                    continue;
+                }
+
+                if !ALLOWED_TAGS.contains(&name.as_str()) || open_tags.len() >= MAX_NESTING_DEPTH {
+                    continue;
+                }
+
+                sanitized.push('<');
+                sanitized.push_str(name.as_str());
+                for (attribute, value) in attributes.iter() {
+                    if is_allowed_attribute(name.as_str(), attribute.as_str(), value.as_str()) {
+                        sanitized.push_str(format!(" {}=\"{}\"", attribute, escape_html(value.as_str())).as_str());
+                    }
+                }
+                sanitized.push('>');
+
+                if VOID_TAGS.contains(&name.as_str()) {
+                    continue;
+                }
+
+                if self_closing {
+                    sanitized.push_str(format!("</{}>", name).as_str());
+                } else {
+                    open_tags.push(name);
+                }
+            },
+            Token::EndTag(name) => {
+                // Closing a tag closes everything opened inside it; stray end tags are dropped
+                match open_tags.iter().rposition(|open| *open == name) {
+                    Some(position) => {
+                        for open in open_tags.drain(position..).rev() {
+                            sanitized.push_str(format!("</{}>", open).as_str());
+                        }
+                    },
+                    None => ()
+                };
+            }
+        };
+    }
+
+    for open in open_tags.drain(..).rev() {
+        sanitized.push_str(format!("</{}>", open).as_str());
+    }
+
+    sanitized
+}
+
+// Renders CommonMark as HTML limited to what formatted_body allows. Raw HTML in the source is
+// sanitized along with everything else.
+pub fn markdown_to_html(markdown: &str) -> String {
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, Parser::new_ext(markdown, Options::ENABLE_TABLES));
+
+    sanitize_html(rendered.trim_end())
+}
+
+// Builds up the plain text rendering of some HTML one token at a time
+struct PlaintextWriter {
+    text: String,
+
+    // One entry per open list: the number of the next item for <ol>, None for <ul>
+    lists: Vec<Option<i64>>,
+
+    blockquote_depth: usize,
+    pre_depth: usize,
+
+    // The href of each open <a>, and where its text starts
+    links: Vec<(Option<String>, usize)>,
+
+    cells_in_row: usize,
+    dropping: Option<(String, usize)>
+}
+
+impl PlaintextWriter {
+    fn new() -> PlaintextWriter {
+        PlaintextWriter {
+            text: String::new(),
+            lists: Vec::new(),
+            blockquote_depth: 0,
+            pre_depth: 0,
+            links: Vec::new(),
+            cells_in_row: 0,
+            dropping: None
+        }
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.text.is_empty() || self.text.ends_with('\n')
+    }
+
+    // Ends the current line, and adds blank lines until there are `count` line breaks in a row
+    fn line_breaks(&mut self, count: usize) -> () {
+        if self.text.is_empty() {
+            return;
+        }
+
+        let existing = self.text.len() - self.text.trim_end_matches('\n').len();
+        for _ in existing..count {
+            self.text.push('\n');
+        }
+    }
+
+    fn write(&mut self, s: &str) -> () {
+        if self.at_line_start() {
+            for _ in 0..self.blockquote_depth {
+                self.text.push_str("> ");
+            }
+        }
+        self.text.push_str(s);
+    }
+
+    fn write_text(&mut self, text: &str) -> () {
+        let decoded = decode_entities(text);
+
+        if self.pre_depth > 0 {
+            let mut lines = decoded.split('\n').peekable();
+            while let Some(line) = lines.next() {
+                if !line.is_empty() {
+                    self.write(line);
+                }
+                if lines.peek().is_some() {
+                    self.text.push('\n');
+                }
+            }
+            return;
+        }
+
+        let mut collapsed = String::with_capacity(decoded.len());
+        let mut last_was_space = self.at_line_start() || self.text.ends_with(' ');
+        for c in decoded.chars() {
+            if c.is_whitespace() && c != '\u{a0}' {
+                if !last_was_space {
+                    collapsed.push(' ');
+                }
+                last_was_space = true;
+            } else {
+                collapsed.push(c);
+                last_was_space = false;
+            }
+        }
+
+        if !collapsed.is_empty() {
+            self.write(collapsed.as_str());
+        }
+    }
+
+    fn start_tag(&mut self, name: &str, attributes: &[(String, String)]) -> () {
+        let attribute = |wanted: &str| attributes.iter().find(|&&(ref a, _)| a == wanted).map(|&(_, ref v)| v.clone());
+
+        match name {
+            "br" => {
+                self.text.push('\n');
+            },
+            "hr" => {
+                self.line_breaks(2);
+                self.write("---");
+                self.line_breaks(2);
+            },
+            "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "table" | "details" => {
+                self.line_breaks(2);
+            },
+            "pre" => {
+                self.line_breaks(2);
+                self.pre_depth += 1;
+            },
+            "blockquote" => {
+                self.line_breaks(2);
+                self.blockquote_depth += 1;
+            },
+            "ul" | "ol" => {
+                self.line_breaks(if self.lists.is_empty() { 2 } else { 1 });
+                let start = if name == "ol" {
+                    Some(attribute("start").and_then(|start| start.parse().ok()).unwrap_or(1))
+                } else {
+                    None
+                };
+                self.lists.push(start);
+            },
+            "li" => {
+                self.line_breaks(1);
+                let indent = "  ".repeat(self.lists.len().saturating_sub(1));
+                let marker = match self.lists.last_mut() {
+                    Some(&mut Some(ref mut number)) => {
+                        *number += 1;
+                        format!("{}{}. ", indent, *number - 1)
+                    },
+                    _ => format!("{}- ", indent)
+                };
+                self.write(marker.as_str());
+            },
+            "div" | "summary" | "caption" => {
+                self.line_breaks(1);
+            },
+            "tr" => {
+                self.line_breaks(1);
+                self.cells_in_row = 0;
+            },
+            "td" | "th" => {
+                if self.cells_in_row > 0 {
+                    self.write(" | ");
+                }
+                self.cells_in_row += 1;
+            },
+            "img" => {
+                match attribute("alt").or(attribute("title")) {
+                    Some(alt) => self.write_text(alt.as_str()),
+                    None => ()
+                };
+            },
+            "a" => {
+                let start = self.text.len();
+                self.links.push((attribute("href"), start));
+            },
+            _ => ()
+        };
+    }
+
+    fn end_tag(&mut self, name: &str) -> () {
+        match name {
+            "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "table" | "details" => {
+                self.line_breaks(2);
+            },
+            "pre" => {
+                self.pre_depth = self.pre_depth.saturating_sub(1);
+                self.line_breaks(2);
+            },
+            "blockquote" => {
+                self.blockquote_depth = self.blockquote_depth.saturating_sub(1);
+                self.line_breaks(2);
+            },
+            "ul" | "ol" => {
+                self.lists.pop();
+                self.line_breaks(if self.lists.is_empty() { 2 } else { 1 });
+            },
+            "li" | "div" | "tr" | "summary" | "caption" => {
+                self.line_breaks(1);
+            },
+            "a" => {
+                match self.links.pop() {
+                    Some((Some(href), start)) => {
+                        let link_text = self.text[start.min(self.text.len())..].trim().to_string();
+                        let target = href.trim_start_matches("mailto:");
+                        if !href.is_empty() && link_text != href && link_text != target {
+                            self.write(format!(" ({})", href).as_str());
+                        }
+                    },
+                    _ => ()
+                };
+            },
+            _ => ()
+        };
+    }
+
+    fn finish(self) -> String {
+        let lines: Vec<&str> = self.text.lines().map(|line| line.trim_end()).collect();
+        lines.join("\n").trim_matches('\n').to_string()
+    }
+}
+
+// Renders HTML as readable plain text, for use as the body of a formatted message
+pub fn html_to_plaintext(html: &str) -> String {
+    let mut writer = PlaintextWriter::new();
+
+    for token in tokenize(html) {
+        if let Some((ref dropped_name, ref mut depth)) = writer.dropping {
+            match token {
+                Token::StartTag { ref name, self_closing, .. } if name == dropped_name && !self_closing => { *depth += 1; },
+                Token::EndTag(ref name) if name == dropped_name => { *depth -= 1; },
+                _ => ()
+            };
+
+            if *depth > 0 {
+                continue;
+            }
+        }
+
+        if writer.dropping.as_ref().map(|&(_, depth)| depth == 0).unwrap_or(false) {
+            writer.dropping = None;
+            continue;
+        }
+
+        match token {
+            Token::Text(text) => writer.write_text(text),
+            Token::StartTag { name, attributes, self_closing } => {
+                if DROPPED_WITH_CONTENTS.contains(&name.as_str()) {
+                    if !self_closing {
+                        writer.dropping = Some((name, 1));
+                    }
+                    continue;
+                }
+
+                writer.start_tag(name.as_str(), &attributes);
+                if self_closing {
+                    writer.end_tag(name.as_str());
+                }
+            },
+            Token::EndTag(name) => writer.end_tag(name.as_str())
+        };
+    }
+
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_markup_is_kept() {
+        let html = "<p>Some <b>bold</b>, <em>emphasis</em> and <code class=\"language-rust\">code</code></p>";
+        assert_eq!(sanitize_html(html), html);
+    }
+
+    #[test]
+    fn disallowed_tags_are_dropped_but_their_text_kept() {
+        assert_eq!(sanitize_html("<marquee>hello <blink>there</blink></marquee>"), "hello there");
+    }
+
+    #[test]
+    fn scripts_and_styles_are_dropped_entirely() {
+        assert_eq!(sanitize_html("a<script>alert('hi')</script>b<style>p { color: red }</style>c"), "abc");
+        assert_eq!(sanitize_html("a<mx-reply><blockquote>quoted</blockquote></mx-reply>b"), "ab");
+    }
+
+    #[test]
+    fn disallowed_attributes_are_dropped() {
+        assert_eq!(sanitize_html("<p onclick=\"evil()\" style=\"x\">hi</p>"), "<p>hi</p>");
+        assert_eq!(sanitize_html("<font color=\"red\" face=\"x\">hi</font>"), "<font color=\"red\">hi</font>");
+        assert_eq!(sanitize_html("<span data-mx-spoiler>hi</span>"), "<span data-mx-spoiler=\"\">hi</span>");
+    }
+
+    #[test]
+    fn links_are_limited_to_safe_schemes() {
+        assert_eq!(sanitize_html("<a href=\"https://example.org/?a=1&amp;b=2\">x</a>"), "<a href=\"https://example.org/?a=1&amp;b=2\">x</a>");
+        assert_eq!(sanitize_html("<a href=\"javascript:alert(1)\">x</a>"), "<a>x</a>");
+        assert_eq!(sanitize_html("<a href=\"java&#115;cript:alert(1)\">x</a>"), "<a>x</a>");
+    }
+
+    #[test]
+    fn images_must_come_from_the_media_repository() {
+        assert_eq!(sanitize_html("<img src=\"mxc://example.org/abc\" alt=\"cat\">"), "<img src=\"mxc://example.org/abc\" alt=\"cat\">");
+        assert_eq!(sanitize_html("<img src=\"https://example.org/track.png\">"), "<img>");
+    }
+
+    #[test]
+    fn unbalanced_tags_are_fixed_up() {
+        assert_eq!(sanitize_html("<b><i>both</b> neither</i>"), "<b><i>both</i></b> neither");
+        assert_eq!(sanitize_html("<ul><li>one"), "<ul><li>one</li></ul>");
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        assert_eq!(sanitize_html("1 < 2 && 3 > 2"), "1 &lt; 2 &amp;&amp; 3 &gt; 2");
+        assert_eq!(sanitize_html("&lt;b&gt;not bold&lt;/b&gt;"), "&lt;b&gt;not bold&lt;/b&gt;");
+    }
+
+    #[test]
+    fn unterminated_attribute_quotes_do_not_panic() {
+        assert_eq!(sanitize_html("<a href=\"https://x"), "<a href=\"https://x\"></a>");
+        assert_eq!(sanitize_html("<a href='javascript:alert(1)"), "<a></a>");
+        assert_eq!(html_to_plaintext("<b title='x"), "");
+        assert_eq!(html_to_plaintext("hi <img alt=\"cat"), "hi cat");
+    }
+
+    #[test]
+    fn tags_cut_off_at_the_end_of_input_do_not_panic() {
+        for html in ["<b", "<b ", "<a href", "<a href=", "<a href= ", "<p class=x", "<br/", "</b", "<!--", "<"].iter() {
+            sanitize_html(html);
+            html_to_plaintext(html);
+        }
+        assert_eq!(sanitize_html("text<a href="), "text<a></a>");
+    }
+
+    #[test]
+    fn deep_nesting_is_cut_off() {
+        let html = "<b>".repeat(150);
+        assert_eq!(sanitize_html(html.as_str()).matches("<b>").count(), MAX_NESTING_DEPTH);
+    }
+
+    #[test]
+    fn markdown_renders_to_allowed_html() {
+        assert_eq!(markdown_to_html("Run `cargo test` with **care**"), "<p>Run <code>cargo test</code> with <strong>care</strong></p>");
+        assert_eq!(markdown_to_html("* one\n* [two](https://example.org)"), "<ul>\n<li>one</li>\n<li><a href=\"https://example.org\">two</a></li>\n</ul>");
+        assert_eq!(markdown_to_html("```rust\nfn main() {}\n```"), "<pre><code class=\"language-rust\">fn main() {}\n</code></pre>");
+    }
+
+    #[test]
+    fn markdown_cannot_sneak_in_html() {
+        assert_eq!(markdown_to_html("hi <script>alert(1)</script> [x](javascript:alert(1))"), "<p>hi  <a>x</a></p>");
+    }
+
+    #[test]
+    fn plaintext_separates_blocks() {
+        assert_eq!(html_to_plaintext("<h1>Title</h1><p>First  paragraph\nwrapped</p><p>Second<br>line</p>"),
+                   "Title\n\nFirst paragraph wrapped\n\nSecond\nline");
+    }
+
+    #[test]
+    fn plaintext_lists_get_markers() {
+        assert_eq!(html_to_plaintext("<ul><li>one</li><li>two<ol start=\"3\"><li>three</li><li>four</li></ol></li></ul>"),
+                   "- one\n- two\n  3. three\n  4. four");
+    }
+
+    #[test]
+    fn plaintext_keeps_code_blocks_as_they_are() {
+        assert_eq!(html_to_plaintext("<p>Code:</p><pre><code>fn main() {\n    println!(\"&lt;hi&gt;\");\n}\n</code></pre>"),
+                   "Code:\n\nfn main() {\n    println!(\"<hi>\");\n}");
+    }
+
+    #[test]
+    fn plaintext_tables_put_cells_on_one_line() {
+        assert_eq!(html_to_plaintext("<table><tr><th>Name</th><th>Score</th></tr><tr><td>alice</td><td>3</td></tr></table>"),
+                   "Name | Score\nalice | 3");
+    }
+
+    #[test]
+    fn plaintext_quotes_and_links() {
+        assert_eq!(html_to_plaintext("<blockquote>quoted<br>twice</blockquote><p>see <a href=\"https://example.org\">this</a></p>"),
+                   "> quoted\n> twice\n\nsee this (https://example.org)");
+        assert_eq!(html_to_plaintext("<a href=\"https://example.org\">https://example.org</a>"), "https://example.org");
+    }
+}
@@ -0,0 +1,448 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de;
+
+// Identifiers are limited to 255 bytes, sigil and server name included
+const MAX_IDENTIFIER_LENGTH: usize = 255;
+
+const MXC_SCHEME: &'static str = "mxc://";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdentifierError {
+    Empty,
+    TooLong,
+
+    // The identifier didn't start with the sigil this kind of identifier needs
+    MissingSigil(char),
+
+    MissingServerName,
+    EmptyLocalpart,
+    InvalidLocalpart,
+    InvalidServerName,
+    MissingScheme,
+    InvalidMediaId
+}
+
+impl fmt::Display for IdentifierError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IdentifierError::Empty => write!(f, "identifier is empty"),
+            IdentifierError::TooLong => write!(f, "identifier is longer than {} bytes", MAX_IDENTIFIER_LENGTH),
+            IdentifierError::MissingSigil(sigil) => write!(f, "identifier does not start with '{}'", sigil),
+            IdentifierError::MissingServerName => write!(f, "identifier has no server name"),
+            IdentifierError::EmptyLocalpart => write!(f, "identifier has an empty localpart"),
+            IdentifierError::InvalidLocalpart => write!(f, "identifier has characters which aren't allowed in its localpart"),
+            IdentifierError::InvalidServerName => write!(f, "server name is not a valid host name or IP address with an optional port"),
+            IdentifierError::MissingScheme => write!(f, "media URI does not start with {}", MXC_SCHEME),
+            IdentifierError::InvalidMediaId => write!(f, "media ID is empty or has characters other than letters, digits, '-' and '_'")
+        }
+    }
+}
+
+// Everything an identifier type needs beyond its own parse function: conversion to and from
+// strings and serializing as a plain string which is validated on the way back in
+macro_rules! identifier_impls {
+    ($name:ident) => {
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                self.0.as_str()
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(self.0.as_str())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = IdentifierError;
+
+            fn from_str(s: &str) -> Result<$name, IdentifierError> {
+                $name::parse(s)
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                self.0.as_str()
+            }
+        }
+
+        // Lets maps keyed by identifiers be looked up with a plain &str
+        impl Borrow<str> for $name {
+            fn borrow(&self) -> &str {
+                self.0.as_str()
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl<'a> PartialEq<&'a str> for $name {
+            fn eq(&self, other: &&'a str) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+                serializer.serialize_str(self.0.as_str())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<$name, D::Error> where D: Deserializer<'de> {
+                let s = String::deserialize(deserializer)?;
+                $name::parse(s.as_str()).map_err(de::Error::custom)
+            }
+        }
+    }
+}
+
+// A host name, IPv4 address or bracketed IPv6 address, optionally followed by a port
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ServerName(String);
+
+impl ServerName {
+    pub fn parse(s: &str) -> Result<ServerName, IdentifierError> {
+        if s.is_empty() {
+            return Err(IdentifierError::Empty);
+        }
+
+        if is_valid_server_name(s) {
+            Ok(ServerName(String::from(s)))
+        } else {
+            Err(IdentifierError::InvalidServerName)
+        }
+    }
+}
+
+identifier_impls!(ServerName);
+
+// @localpart:server.name
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct UserId(String);
+
+impl UserId {
+    pub fn parse(s: &str) -> Result<UserId, IdentifierError> {
+        let (localpart, _) = parse_sigil_and_server_name(s, '@')?;
+
+        // Historical user IDs are allowed any printable ASCII, so only reject what can never be valid
+        if !localpart.chars().all(|c| c.is_ascii_graphic()) {
+            return Err(IdentifierError::InvalidLocalpart);
+        }
+
+        Ok(UserId(String::from(s)))
+    }
+}
+
+identifier_impls!(UserId);
+
+// !opaque_id:server.name
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RoomId(String);
+
+impl RoomId {
+    pub fn parse(s: &str) -> Result<RoomId, IdentifierError> {
+        parse_sigil_and_server_name(s, '!')?;
+        Ok(RoomId(String::from(s)))
+    }
+}
+
+identifier_impls!(RoomId);
+
+// #room_alias:server.name
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RoomAliasId(String);
+
+impl RoomAliasId {
+    pub fn parse(s: &str) -> Result<RoomAliasId, IdentifierError> {
+        parse_sigil_and_server_name(s, '#')?;
+        Ok(RoomAliasId(String::from(s)))
+    }
+}
+
+identifier_impls!(RoomAliasId);
+
+// $opaque_id:server.name in rooms up to version 2; newer room versions drop the server name and
+// use a hash of the event instead
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EventId(String);
+
+impl EventId {
+    pub fn parse(s: &str) -> Result<EventId, IdentifierError> {
+        if s.contains(':') {
+            parse_sigil_and_server_name(s, '$')?;
+        } else {
+            check_sigil(s, '$')?;
+            if s.len() == 1 {
+                return Err(IdentifierError::EmptyLocalpart);
+            }
+        }
+
+        Ok(EventId(String::from(s)))
+    }
+}
+
+identifier_impls!(EventId);
+
+// Either of the two ways a room can be named when joining it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RoomIdOrAliasId {
+    RoomId(RoomId),
+    RoomAliasId(RoomAliasId)
+}
+
+impl RoomIdOrAliasId {
+    pub fn parse(s: &str) -> Result<RoomIdOrAliasId, IdentifierError> {
+        if s.starts_with('#') {
+            RoomAliasId::parse(s).map(RoomIdOrAliasId::RoomAliasId)
+        } else {
+            RoomId::parse(s).map(RoomIdOrAliasId::RoomId)
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match *self {
+            RoomIdOrAliasId::RoomId(ref room_id) => room_id.as_str(),
+            RoomIdOrAliasId::RoomAliasId(ref room_alias_id) => room_alias_id.as_str()
+        }
+    }
+}
+
+impl fmt::Display for RoomIdOrAliasId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for RoomIdOrAliasId {
+    type Err = IdentifierError;
+
+    fn from_str(s: &str) -> Result<RoomIdOrAliasId, IdentifierError> {
+        RoomIdOrAliasId::parse(s)
+    }
+}
+
+impl From<RoomId> for RoomIdOrAliasId {
+    fn from(room_id: RoomId) -> RoomIdOrAliasId {
+        RoomIdOrAliasId::RoomId(room_id)
+    }
+}
+
+impl From<RoomAliasId> for RoomIdOrAliasId {
+    fn from(room_alias_id: RoomAliasId) -> RoomIdOrAliasId {
+        RoomIdOrAliasId::RoomAliasId(room_alias_id)
+    }
+}
+
+// mxc://server.name/media_id
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MxcUri(String);
+
+impl MxcUri {
+    pub fn parse(s: &str) -> Result<MxcUri, IdentifierError> {
+        if !s.starts_with(MXC_SCHEME) {
+            return Err(IdentifierError::MissingScheme);
+        }
+
+        let mxc = MxcUri(String::from(s));
+
+        ServerName::parse(mxc.server_name()).map_err(|_| IdentifierError::InvalidServerName)?;
+
+        let media_id = mxc.media_id();
+        let media_id_is_valid = !media_id.is_empty() && media_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !media_id_is_valid {
+            return Err(IdentifierError::InvalidMediaId);
+        }
+
+        Ok(mxc)
+    }
+
+    pub fn server_name(&self) -> &str {
+        let rest = &self.0[MXC_SCHEME.len()..];
+        &rest[..rest.find('/').unwrap_or(rest.len())]
+    }
+
+    pub fn media_id(&self) -> &str {
+        let rest = &self.0[MXC_SCHEME.len()..];
+        &rest[rest.find('/').map(|slash| slash + 1).unwrap_or(rest.len())..]
+    }
+}
+
+identifier_impls!(MxcUri);
+
+// For lists of identifiers in homeserver responses, where one malformed entry shouldn't make the
+// whole response unreadable. Anything which doesn't parse is left out.
+pub fn deserialize_valid_ids<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where D: Deserializer<'de>, T: FromStr {
+    let ids = Vec::<String>::deserialize(deserializer)?;
+    Ok(ids.iter().filter_map(|id| id.parse().ok()).collect())
+}
+
+// The same for maps keyed by identifiers, e.g. the rooms in a sync
+pub fn deserialize_valid_id_keys<'de, D, K, V>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where D: Deserializer<'de>, K: FromStr + Eq + Hash, V: Deserialize<'de> {
+    let map = HashMap::<String, V>::deserialize(deserializer)?;
+    Ok(map.into_iter().filter_map(|(id, value)| id.parse().ok().map(|id| (id, value))).collect())
+}
+
+fn check_sigil(s: &str, sigil: char) -> Result<(), IdentifierError> {
+    if s.is_empty() {
+        return Err(IdentifierError::Empty);
+    }
+
+    if s.len() > MAX_IDENTIFIER_LENGTH {
+        return Err(IdentifierError::TooLong);
+    }
+
+    if !s.starts_with(sigil) {
+        return Err(IdentifierError::MissingSigil(sigil));
+    }
+
+    Ok(())
+}
+
+// Checks the sigil and server name of a <sigil>localpart:server.name identifier, returning the
+// localpart and server name
+fn parse_sigil_and_server_name(s: &str, sigil: char) -> Result<(&str, &str), IdentifierError> {
+    check_sigil(s, sigil)?;
+
+    if !s.contains(':') {
+        return Err(IdentifierError::MissingServerName);
+    }
+
+    let (localpart, server_name) = split_server_name(s);
+
+    if localpart.is_empty() {
+        return Err(IdentifierError::EmptyLocalpart);
+    }
+
+    if !is_valid_server_name(server_name) {
+        return Err(IdentifierError::InvalidServerName);
+    }
+
+    Ok((localpart, server_name))
+}
+
+// The localpart can't contain a ':', but the server name can (ports, IPv6), so split on the first
+fn split_server_name(s: &str) -> (&str, &str) {
+    let without_sigil = &s[1..];
+    match without_sigil.find(':') {
+        Some(colon) => (&without_sigil[..colon], &without_sigil[colon + 1..]),
+        None => (without_sigil, "")
+    }
+}
+
+fn split_port(server_name: &str) -> (&str, Option<&str>) {
+    // The port comes after the last ':', unless that ':' is inside an IPv6 literal
+    match server_name.rfind(':') {
+        Some(colon) if !server_name[colon..].contains(']') => (&server_name[..colon], Some(&server_name[colon + 1..])),
+        _ => (server_name, None)
+    }
+}
+
+fn is_valid_server_name(server_name: &str) -> bool {
+    let (host, port) = split_port(server_name);
+
+    let port_is_valid = match port {
+        Some(port) => !port.is_empty() && port.len() <= 5 && port.chars().all(|c| c.is_ascii_digit()),
+        None => true
+    };
+
+    let host_is_valid = if host.starts_with('[') {
+        host.len() > 2 && host.ends_with(']')
+            && host[1..host.len() - 1].chars().all(|c| c.is_ascii_hexdigit() || c == ':' || c == '.')
+    } else {
+        !host.is_empty() && host.len() <= MAX_IDENTIFIER_LENGTH
+            && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    };
+
+    port_is_valid && host_is_valid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn server_names_may_have_ports_and_ip_literals() {
+        assert!(UserId::parse("@alice:example.org").is_ok());
+        assert!(UserId::parse("@alice:[2001:db8::1]:8448").is_ok());
+
+        assert!(ServerName::parse("[2001:db8::1]:8448").is_ok());
+        assert!(ServerName::parse("[::1]").is_ok());
+        assert!(ServerName::parse("127.0.0.1:80").is_ok());
+
+        assert_eq!(ServerName::parse("example.org:"), Err(IdentifierError::InvalidServerName));
+        assert_eq!(ServerName::parse("[::1"), Err(IdentifierError::InvalidServerName));
+    }
+
+    #[test]
+    fn sigils_are_checked() {
+        assert_eq!(RoomId::parse("#lobby:example.org"), Err(IdentifierError::MissingSigil('!')));
+        assert_eq!(RoomAliasId::parse("!abc:example.org"), Err(IdentifierError::MissingSigil('#')));
+        assert_eq!(UserId::parse("alice:example.org"), Err(IdentifierError::MissingSigil('@')));
+        assert_eq!(EventId::parse("abc"), Err(IdentifierError::MissingSigil('$')));
+    }
+
+    #[test]
+    fn malformed_identifiers_are_rejected() {
+        assert_eq!(UserId::parse(""), Err(IdentifierError::Empty));
+        assert_eq!(UserId::parse("@alice"), Err(IdentifierError::MissingServerName));
+        assert_eq!(UserId::parse("@:example.org"), Err(IdentifierError::EmptyLocalpart));
+        assert_eq!(UserId::parse("@al ice:example.org"), Err(IdentifierError::InvalidLocalpart));
+        assert_eq!(RoomId::parse("!abc:"), Err(IdentifierError::InvalidServerName));
+        assert_eq!(RoomId::parse("!abc:exa mple.org"), Err(IdentifierError::InvalidServerName));
+        assert_eq!(RoomId::parse("!abc:example.org:port"), Err(IdentifierError::InvalidServerName));
+        assert_eq!(UserId::parse(format!("@{}:example.org", "a".repeat(255)).as_str()), Err(IdentifierError::TooLong));
+    }
+
+    #[test]
+    fn event_ids_may_not_have_a_server_name() {
+        assert!(EventId::parse("$abc:example.org").is_ok());
+        assert!(EventId::parse("$acR1l0raoZnm60CBwAVgqbZqoO/mYU81xysh1u7XcJk").is_ok());
+        assert_eq!(EventId::parse("$abc:exa mple.org"), Err(IdentifierError::InvalidServerName));
+
+        assert_eq!(EventId::parse("$"), Err(IdentifierError::EmptyLocalpart));
+    }
+
+    #[test]
+    fn room_id_or_alias_goes_by_sigil() {
+        assert_eq!(RoomIdOrAliasId::parse("#lobby:example.org").unwrap(),
+                   RoomIdOrAliasId::RoomAliasId(RoomAliasId::parse("#lobby:example.org").unwrap()));
+        assert_eq!(RoomIdOrAliasId::parse("!abc:example.org").unwrap(),
+                   RoomIdOrAliasId::RoomId(RoomId::parse("!abc:example.org").unwrap()));
+        assert!(RoomIdOrAliasId::parse("@alice:example.org").is_err());
+    }
+
+    #[test]
+    fn mxc_uris_split_into_server_name_and_media_id() {
+        let mxc = MxcUri::parse("mxc://example.org/SEsfnsuifSDFSSEF").unwrap();
+        assert_eq!(mxc.server_name(), "example.org");
+        assert_eq!(mxc.media_id(), "SEsfnsuifSDFSSEF");
+
+        assert_eq!(MxcUri::parse("https://example.org/abc"), Err(IdentifierError::MissingScheme));
+        assert_eq!(MxcUri::parse("mxc://example.org"), Err(IdentifierError::InvalidMediaId));
+        assert_eq!(MxcUri::parse("mxc://example.org/a/b"), Err(IdentifierError::InvalidMediaId));
+        assert_eq!(MxcUri::parse("mxc:///abc"), Err(IdentifierError::InvalidServerName));
+    }
+
+    #[test]
+    fn identifiers_serialize_as_plain_strings() {
+        let user_id = UserId::parse("@alice:example.org").unwrap();
+        assert_eq!(serde_json::to_string(&user_id).unwrap(), r#""@alice:example.org""#);
+        assert_eq!(serde_json::from_str::<UserId>(r#""@alice:example.org""#).unwrap(), user_id);
+        assert!(serde_json::from_str::<UserId>(r##""#lobby:example.org""##).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Somewhere to keep the next_batch token from the last sync we dealt with, so that a restarted
+// bot can pick up where it left off rather than missing everything sent while it was down
+pub trait SyncTokenStore {
+    // None if no token has been saved yet
+    fn load(&self) -> io::Result<Option<String>>;
+
+    fn save(&mut self, token: &str) -> io::Result<()>;
+
+    // Forgets the saved token, e.g. once the homeserver has refused it
+    fn clear(&mut self) -> io::Result<()>;
+}
+
+// Keeps the token in a file of its own, replacing it whole on every save so a crash part way
+// through can't leave half a token behind
+pub struct FileSyncTokenStore {
+    path: PathBuf
+}
+
+impl FileSyncTokenStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileSyncTokenStore {
+        FileSyncTokenStore {
+            path: path.as_ref().to_path_buf()
+        }
+    }
+
+    // Where a user's token is kept unless told otherwise
+    pub fn default_path(username: &str) -> PathBuf {
+        PathBuf::from(format!("{}.sync_token", username))
+    }
+}
+
+impl SyncTokenStore for FileSyncTokenStore {
+    fn load(&self) -> io::Result<Option<String>> {
+        match fs::read_to_string(&self.path) {
+            Ok(token) => {
+                let token = token.trim();
+                Ok(if token.is_empty() { None } else { Some(String::from(token)) })
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn save(&mut self, token: &str) -> io::Result<()> {
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+
+        fs::write(&temp_path, token)?;
+        fs::rename(&temp_path, &self.path)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result
+        }
+    }
+}
+
+// Keeps the token in memory only. Clones share the same token.
+#[derive(Clone, Default)]
+pub struct MemorySyncTokenStore {
+    token: Arc<Mutex<Option<String>>>
+}
+
+impl MemorySyncTokenStore {
+    pub fn new(token: Option<&str>) -> MemorySyncTokenStore {
+        MemorySyncTokenStore {
+            token: Arc::new(Mutex::new(token.map(String::from)))
+        }
+    }
+}
+
+impl SyncTokenStore for MemorySyncTokenStore {
+    fn load(&self) -> io::Result<Option<String>> {
+        Ok(self.token.lock().unwrap_or_else(|e| e.into_inner()).clone())
+    }
+
+    fn save(&mut self, token: &str) -> io::Result<()> {
+        *self.token.lock().unwrap_or_else(|e| e.into_inner()) = Some(String::from(token));
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        *self.token.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("jacobian-test-{}-{}", process::id(), name))
+    }
+
+    #[test]
+    fn file_store_starts_empty_and_keeps_the_last_token() {
+        let path = temp_path("sync-token");
+        let _ = fs::remove_file(&path);
+        let mut store = FileSyncTokenStore::new(&path);
+
+        assert_eq!(store.load().unwrap(), None);
+
+        store.save("s1").unwrap();
+        store.save("s2").unwrap();
+
+        assert_eq!(FileSyncTokenStore::new(&path).load().unwrap(), Some(String::from("s2")));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_store_can_be_cleared_whether_or_not_it_has_a_token() {
+        let path = temp_path("sync-token-clear");
+        let mut store = FileSyncTokenStore::new(&path);
+
+        store.save("s1").unwrap();
+        store.clear().unwrap();
+        assert_eq!(store.load().unwrap(), None);
+
+        store.clear().unwrap();
+    }
+
+    #[test]
+    fn file_store_ignores_surrounding_whitespace() {
+        let path = temp_path("sync-token-whitespace");
+        fs::write(&path, "s72595_4483\n").unwrap();
+
+        assert_eq!(FileSyncTokenStore::new(&path).load().unwrap(), Some(String::from("s72595_4483")));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn memory_store_clones_share_the_token() {
+        let store = MemorySyncTokenStore::new(None);
+        let mut clone = store.clone();
+
+        clone.save("s1").unwrap();
+
+        assert_eq!(store.load().unwrap(), Some(String::from("s1")));
+    }
+}
@@ -0,0 +1,276 @@
+extern crate futures;
+extern crate reqwest;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::{Future, Stream};
+
+use matrix_client::MatrixClientError;
+use matrix_core::*;
+
+// Sends a single request to the homeserver and reads back the whole response. Retries, timeouts
+// across attempts and interpreting the response are all left to MatrixClient, so a transport only
+// has to move bytes. Wrap another transport to add logging or metrics, or swap in an in-memory
+// one to talk to a fake homeserver in tests.
+pub trait HttpTransport: Send + Sync {
+    // `timeouts` are the ones which apply to this one attempt. Running out of time should be
+    // reported as MatrixClientError::Timeout.
+    fn send(&self, request: &MatrixRequest, timeouts: &Timeouts) -> Result<HttpResponse, MatrixClientError>;
+}
+
+impl<T: HttpTransport + ?Sized> HttpTransport for Box<T> {
+    fn send(&self, request: &MatrixRequest, timeouts: &Timeouts) -> Result<HttpResponse, MatrixClientError> {
+        (**self).send(request, timeouts)
+    }
+}
+
+impl<T: HttpTransport + ?Sized> HttpTransport for Arc<T> {
+    fn send(&self, request: &MatrixRequest, timeouts: &Timeouts) -> Result<HttpResponse, MatrixClientError> {
+        (**self).send(request, timeouts)
+    }
+}
+
+// The same as HttpTransport, but for AsyncMatrixClient. The returned future must not need
+// anything other than a tokio runtime to make progress.
+pub trait AsyncHttpTransport: Send + Sync {
+    fn send(&self, request: &MatrixRequest, timeouts: &Timeouts) -> Box<dyn Future<Item = HttpResponse, Error = MatrixClientError> + Send>;
+}
+
+impl<T: AsyncHttpTransport + ?Sized> AsyncHttpTransport for Box<T> {
+    fn send(&self, request: &MatrixRequest, timeouts: &Timeouts) -> Box<dyn Future<Item = HttpResponse, Error = MatrixClientError> + Send> {
+        (**self).send(request, timeouts)
+    }
+}
+
+impl<T: AsyncHttpTransport + ?Sized> AsyncHttpTransport for Arc<T> {
+    fn send(&self, request: &MatrixRequest, timeouts: &Timeouts) -> Box<dyn Future<Item = HttpResponse, Error = MatrixClientError> + Send> {
+        (**self).send(request, timeouts)
+    }
+}
+
+// reqwest only lets timeouts be set when a client is built, so the reqwest transports keep one
+// around per set of timeouts
+struct HttpClients<C> {
+    clients: Mutex<HashMap<Timeouts, C>>
+}
+
+impl<C: Clone> HttpClients<C> {
+    fn new() -> HttpClients<C> {
+        HttpClients {
+            clients: Mutex::new(HashMap::new())
+        }
+    }
+
+    fn get_or_build<F>(&self, timeouts: &Timeouts, build: F) -> Result<C, MatrixClientError>
+        where F: FnOnce(&Timeouts) -> reqwest::Result<C> {
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+
+        match clients.get(timeouts) {
+            Some(client) => { return Ok(client.clone()); },
+            None => ()
+        };
+
+        let client = build(timeouts).map_err(MatrixClientError::Http)?;
+
+        if clients.len() >= HTTP_CLIENT_CACHE_SIZE {
+            clients.clear();
+        }
+        clients.insert(*timeouts, client.clone());
+
+        Ok(client)
+    }
+}
+
+impl<C: Clone> Default for HttpClients<C> {
+    fn default() -> HttpClients<C> {
+        HttpClients::new()
+    }
+}
+
+// The default transport, sending requests over the network with a blocking reqwest client
+#[derive(Default)]
+pub struct ReqwestTransport {
+    http_clients: HttpClients<reqwest::Client>
+}
+
+impl ReqwestTransport {
+    pub fn new() -> ReqwestTransport {
+        ReqwestTransport {
+            http_clients: HttpClients::new()
+        }
+    }
+
+    fn http_client_for(&self, timeouts: &Timeouts) -> Result<reqwest::Client, MatrixClientError> {
+        self.http_clients.get_or_build(timeouts, |timeouts| {
+            reqwest::Client::builder()
+                .connect_timeout(timeouts.connect)
+                .timeout(timeouts.request_timeout())
+                .build()
+        })
+    }
+}
+
+impl HttpTransport for ReqwestTransport {
+    fn send(&self, request: &MatrixRequest, timeouts: &Timeouts) -> Result<HttpResponse, MatrixClientError> {
+        let http_client = self.http_client_for(timeouts)?;
+
+        let mut request_builder = http_client.request(request.method.clone(), request.url.clone());
+
+        match request.access_token {
+            Some(ref access_token) => {
+                request_builder = request_builder.bearer_auth(access_token.as_str());
+            },
+            None => ()
+        };
+
+        match (&request.content_type, &request.body) {
+            (&Some(ref content_type), &Some(ref body)) => {
+                request_builder = request_builder.header(reqwest::header::CONTENT_TYPE, content_type.as_str()).body(body.clone());
+            },
+            _ => ()
+        };
+
+        let mut response = request_builder.send().map_err(from_reqwest_error)?;
+
+        let mut body = Vec::new();
+        response.copy_to(&mut body).map_err(from_reqwest_error)?;
+
+        Ok(HttpResponse {
+            status: response.status(),
+            content_type: content_type_of(response.headers()),
+            body: body
+        })
+    }
+}
+
+// The default async transport, sending requests over the network with an async reqwest client
+#[derive(Default)]
+pub struct ReqwestAsyncTransport {
+    http_clients: HttpClients<reqwest::async::Client>
+}
+
+impl ReqwestAsyncTransport {
+    pub fn new() -> ReqwestAsyncTransport {
+        ReqwestAsyncTransport {
+            http_clients: HttpClients::new()
+        }
+    }
+
+    fn http_client_for(&self, timeouts: &Timeouts) -> Result<reqwest::async::Client, MatrixClientError> {
+        self.http_clients.get_or_build(timeouts, |timeouts| {
+            let mut builder = reqwest::async::Client::builder();
+
+            match timeouts.connect {
+                Some(connect) => { builder = builder.connect_timeout(connect); },
+                None => ()
+            };
+
+            match timeouts.request_timeout() {
+                Some(request_timeout) => { builder = builder.timeout(request_timeout); },
+                None => ()
+            };
+
+            builder.build()
+        })
+    }
+}
+
+impl AsyncHttpTransport for ReqwestAsyncTransport {
+    fn send(&self, request: &MatrixRequest, timeouts: &Timeouts) -> Box<dyn Future<Item = HttpResponse, Error = MatrixClientError> + Send> {
+        let http_client = match self.http_client_for(timeouts) {
+            Ok(http_client) => http_client,
+            Err(e) => { return Box::new(futures::future::err(e)); }
+        };
+
+        let mut request_builder = http_client.request(request.method.clone(), request.url.clone());
+
+        match request.access_token {
+            Some(ref access_token) => {
+                request_builder = request_builder.bearer_auth(access_token.as_str());
+            },
+            None => ()
+        };
+
+        match (&request.content_type, &request.body) {
+            (&Some(ref content_type), &Some(ref body)) => {
+                request_builder = request_builder.header(reqwest::header::CONTENT_TYPE, content_type.as_str()).body(body.clone());
+            },
+            _ => ()
+        };
+
+        Box::new(request_builder.send().and_then(|response| {
+            let status = response.status();
+            let content_type = content_type_of(response.headers());
+
+            response.into_body().concat2().map(move |body| HttpResponse {
+                status: status,
+                content_type: content_type,
+                body: body.to_vec()
+            })
+        }).map_err(from_reqwest_error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fake_homeserver::quick_retries;
+    use matrix_client::MatrixClient;
+
+    // Answers every request with the next scripted response and remembers what it was sent
+    struct RecordingTransport {
+        responses: Mutex<Vec<(u16, &'static str)>>,
+        requests: Mutex<Vec<MatrixRequest>>
+    }
+
+    impl RecordingTransport {
+        fn new(mut responses: Vec<(u16, &'static str)>) -> RecordingTransport {
+            responses.reverse();
+            RecordingTransport {
+                responses: Mutex::new(responses),
+                requests: Mutex::new(Vec::new())
+            }
+        }
+    }
+
+    impl HttpTransport for RecordingTransport {
+        fn send(&self, request: &MatrixRequest, _timeouts: &Timeouts) -> Result<HttpResponse, MatrixClientError> {
+            self.requests.lock().unwrap().push(request.clone());
+            let (status, body) = self.responses.lock().unwrap().pop().expect("no response scripted");
+
+            Ok(HttpResponse {
+                status: reqwest::StatusCode::from_u16(status).unwrap(),
+                content_type: Some(String::from("application/json")),
+                body: body.as_bytes().to_vec()
+            })
+        }
+    }
+
+    #[test]
+    fn requests_go_through_the_transport() {
+        let transport = Arc::new(RecordingTransport::new(vec![(200, r#"{"versions": ["r0.5.0"]}"#)]));
+        let client = MatrixClient::with_transport("https://matrix.example.org", None, Box::new(transport.clone()));
+
+        let versions = client.get_supported_versions().unwrap();
+
+        assert_eq!(versions.versions, vec![String::from("r0.5.0")]);
+        let requests = transport.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, reqwest::Method::GET);
+        assert_eq!(requests[0].url.as_str(), "https://matrix.example.org/_matrix/client/versions");
+    }
+
+    #[test]
+    fn server_errors_are_retried_on_the_same_transport() {
+        let transport = Arc::new(RecordingTransport::new(vec![
+            (503, r#"{"errcode": "M_UNKNOWN"}"#),
+            (200, r#"{"versions": []}"#)
+        ]));
+        let mut client = MatrixClient::with_transport("https://matrix.example.org", None, Box::new(transport.clone()));
+        client.set_retry_policy(quick_retries());
+
+        assert!(client.get_supported_versions().is_ok());
+        assert_eq!(transport.requests.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,83 @@
+extern crate reqwest;
+
+pub const CLIENT_API_PREFIX: [&'static str; 3] = ["_matrix", "client", "r0"];
+pub const MEDIA_API_PREFIX: [&'static str; 3] = ["_matrix", "media", "r0"];
+
+// Appends each segment to the homeserver's URL, percent-encoding anything which can't appear
+// literally inside a single path segment ('#', '/', '?', spaces, non-ASCII, ...). Any path the
+// homeserver URL already has is kept, so servers hosted under a prefix work too.
+pub fn build_url(homeserver: &str, prefix: &[&str], segments: &[&str]) -> Result<reqwest::Url, reqwest::UrlError> {
+    let mut url = reqwest::Url::parse(homeserver)?;
+
+    {
+        let mut path = url.path_segments_mut().map_err(|_| reqwest::UrlError::RelativeUrlWithCannotBeABaseBase)?;
+        path.pop_if_empty();
+        path.extend(prefix);
+        path.extend(segments);
+    }
+
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_identifiers::{EventId, RoomAliasId};
+
+    fn client_url(segments: &[&str]) -> String {
+        build_url("https://matrix.example.org", &CLIENT_API_PREFIX, segments).unwrap().into_string()
+    }
+
+    #[test]
+    fn plain_segments_are_joined() {
+        assert_eq!(client_url(&["login"]), "https://matrix.example.org/_matrix/client/r0/login");
+        assert_eq!(client_url(&["logout", "all"]), "https://matrix.example.org/_matrix/client/r0/logout/all");
+    }
+
+    #[test]
+    fn room_ids_keep_their_sigil_and_colon() {
+        assert_eq!(client_url(&["rooms", "!abc123:example.org", "messages"]),
+                   "https://matrix.example.org/_matrix/client/r0/rooms/!abc123:example.org/messages");
+    }
+
+    #[test]
+    fn room_aliases_are_not_treated_as_fragments() {
+        assert_eq!(client_url(&["join", "#room:example.org"]),
+                   "https://matrix.example.org/_matrix/client/r0/join/%23room:example.org");
+    }
+
+    #[test]
+    fn slashes_inside_a_segment_are_escaped() {
+        assert_eq!(client_url(&["rooms", "!a/b:example.org", "state", "m.room.name"]),
+                   "https://matrix.example.org/_matrix/client/r0/rooms/!a%2Fb:example.org/state/m.room.name");
+    }
+
+    #[test]
+    fn query_characters_inside_a_segment_are_escaped() {
+        assert_eq!(client_url(&["rooms", "!room:example.org", "send", "m.room.message", "txn?1 2"]),
+                   "https://matrix.example.org/_matrix/client/r0/rooms/!room:example.org/send/m.room.message/txn%3F1%202");
+    }
+
+    #[test]
+    fn identifiers_keep_characters_which_would_end_a_segment() {
+        let event_id = EventId::parse("$acR1l0raoZnm60CBwAVgqbZqoO/mYU81xysh1u7XcJk").unwrap();
+        assert_eq!(client_url(&["rooms", "!room:example.org", "redact", event_id.as_str(), "txn1"]),
+                   "https://matrix.example.org/_matrix/client/r0/rooms/!room:example.org/redact/$acR1l0raoZnm60CBwAVgqbZqoO%2FmYU81xysh1u7XcJk/txn1");
+
+        let alias = RoomAliasId::parse("#what?#now:example.org").unwrap();
+        assert_eq!(client_url(&["directory", "room", alias.as_str()]),
+                   "https://matrix.example.org/_matrix/client/r0/directory/room/%23what%3F%23now:example.org");
+    }
+
+    #[test]
+    fn homeserver_path_prefix_is_kept() {
+        let url = build_url("https://example.org/matrix/", &MEDIA_API_PREFIX, &["download", "example.org", "abc"]).unwrap();
+        assert_eq!(url.as_str(), "https://example.org/matrix/_matrix/media/r0/download/example.org/abc");
+    }
+
+    #[test]
+    fn unusable_homeserver_is_an_error() {
+        assert!(build_url("not a url", &CLIENT_API_PREFIX, &["login"]).is_err());
+        assert!(build_url("mailto:someone@example.org", &CLIENT_API_PREFIX, &["login"]).is_err());
+    }
+}